@@ -12,44 +12,168 @@ fn main() {
     &mut target,
     {        // generates types and encoders for types implementing ElmEncoder
     encoders: [ od::UserId,
+      od::OrgId ,
+      od::GroupId ,
       od::LoginData ,
       od::AdminSettings ,
       od::User ,
       od::PhantomUser ,
+      od::DeletedUser ,
       od::UserInvite ,
       od::GetInvite ,
+      od::Role ,
+      od::UserRole ,
+      od::OrgRole ,
+      od::Org ,
+      od::OrgMembership ,
+      od::OrgMember ,
+      od::OrgMembers ,
+      od::OrgUser ,
+      od::SetOrgMemberRole ,
+      od::Group ,
+      od::GroupMember ,
+      od::GroupInfo ,
+      od::RenameGroup ,
+      od::GroupUser ,
+      od::Subgroup ,
+      od::UserGroups ,
+      od::ProfileFieldType ,
+      od::ProfileFieldDef ,
+      od::ProfileFieldValue ,
+      od::UserProfile ,
+      od::ProfileFieldError ,
+      od::UsernameError ,
       od::RegistrationData ,
       od::RSVP ,
       od::Login ,
       od::ResetPassword ,
+      od::ResendRegistration ,
       od::PwdReset ,
       od::SetPassword ,
       od::ChangePassword ,
       od::ChangeEmail ,
       od::ChangeRemoteUrl ,
+      od::ChangeUserName ,
+      od::DeleteAccount ,
+      od::Suspension ,
+      od::SuspendUser ,
+      od::SuspensionNotice ,
+      od::AdminUser ,
+      od::UserSort ,
+      od::UserQuery ,
+      od::UserPage ,
+      od::ImportUser ,
+      od::ImportSource ,
+      od::ImportMode ,
+      od::ImportUsers ,
+      od::ImportOutcome ,
+      od::ImportRow ,
+      od::BulkOp ,
+      od::BulkUserOp ,
+      od::BulkOutcome ,
+      od::BulkResult ,
+      od::AuditEvent ,
+      od::AuditQuery ,
+      od::NewUserPassword ,
+      od::CreateUser ,
+      od::CreatedUser ,
+      od::ChangePasswordLogin ,
+      od::InviteUse ,
+      od::InviteInfo ,
+      od::InviteQuota ,
+      od::InviteQuotaStatus ,
+      od::Referral ,
+      od::PendingRegistration ,
+      od::RegistrationDecision ,
+      od::PendingEmailChange ,
       od::UserRequest ,
       od::AuthedRequest ,
+      od::OrgRequest ,
+      od::GroupRequest ,
+      od::GroupResponse ,
       od::UserResponse ,
       od::AdminRequest ,
       od::AdminResponse ,]
     decoders: [ od::UserId,
+      od::OrgId ,
+      od::GroupId ,
       od::LoginData ,
       od::AdminSettings ,
       od::User ,
       od::PhantomUser ,
+      od::DeletedUser ,
       od::UserInvite ,
       od::GetInvite ,
+      od::Role ,
+      od::UserRole ,
+      od::OrgRole ,
+      od::Org ,
+      od::OrgMembership ,
+      od::OrgMember ,
+      od::OrgMembers ,
+      od::OrgUser ,
+      od::SetOrgMemberRole ,
+      od::Group ,
+      od::GroupMember ,
+      od::GroupInfo ,
+      od::RenameGroup ,
+      od::GroupUser ,
+      od::Subgroup ,
+      od::UserGroups ,
+      od::ProfileFieldType ,
+      od::ProfileFieldDef ,
+      od::ProfileFieldValue ,
+      od::UserProfile ,
+      od::ProfileFieldError ,
+      od::UsernameError ,
       od::RegistrationData ,
       od::RSVP ,
       od::Login ,
       od::ResetPassword ,
+      od::ResendRegistration ,
       od::PwdReset ,
       od::SetPassword ,
       od::ChangePassword ,
       od::ChangeEmail ,
       od::ChangeRemoteUrl ,
+      od::ChangeUserName ,
+      od::DeleteAccount ,
+      od::Suspension ,
+      od::SuspendUser ,
+      od::SuspensionNotice ,
+      od::AdminUser ,
+      od::UserSort ,
+      od::UserQuery ,
+      od::UserPage ,
+      od::ImportUser ,
+      od::ImportSource ,
+      od::ImportMode ,
+      od::ImportUsers ,
+      od::ImportOutcome ,
+      od::ImportRow ,
+      od::BulkOp ,
+      od::BulkUserOp ,
+      od::BulkOutcome ,
+      od::BulkResult ,
+      od::AuditEvent ,
+      od::AuditQuery ,
+      od::NewUserPassword ,
+      od::CreateUser ,
+      od::CreatedUser ,
+      od::ChangePasswordLogin ,
+      od::InviteUse ,
+      od::InviteInfo ,
+      od::InviteQuota ,
+      od::InviteQuotaStatus ,
+      od::Referral ,
+      od::PendingRegistration ,
+      od::RegistrationDecision ,
+      od::PendingEmailChange ,
       od::UserRequest ,
       od::AuthedRequest ,
+      od::OrgRequest ,
+      od::GroupRequest ,
+      od::GroupResponse ,
       od::UserResponse ,
       od::AdminRequest ,
       od::AdminResponse ,]
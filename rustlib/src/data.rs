@@ -38,6 +38,60 @@ impl Display for UserId {
   }
 }
 
+#[derive(Elm, ElmDecode, ElmEncode, Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum OrgId {
+  Oid(i64),
+}
+
+impl OrgId {
+  pub fn to_i64(&self) -> &i64 {
+    match self {
+      OrgId::Oid(id) => id,
+    }
+  }
+}
+
+impl From<i64> for OrgId {
+  fn from(a: i64) -> Self {
+    OrgId::Oid(a)
+  }
+}
+
+impl Display for OrgId {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      OrgId::Oid(id) => write!(f, "{}", id),
+    }
+  }
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Serialize, Deserialize, PartialEq, Eq, Debug, Clone, Copy)]
+pub enum GroupId {
+  Gid(i64),
+}
+
+impl GroupId {
+  pub fn to_i64(&self) -> &i64 {
+    match self {
+      GroupId::Gid(id) => id,
+    }
+  }
+}
+
+impl From<i64> for GroupId {
+  fn from(a: i64) -> Self {
+    GroupId::Gid(a)
+  }
+}
+
+impl Display for GroupId {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      GroupId::Gid(id) => write!(f, "{}", id),
+    }
+  }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct Config {
   pub mainsite: String,
@@ -54,6 +108,116 @@ pub struct Config {
   pub send_emails: bool,
   pub non_admin_invite: bool,
   pub remote_registration: bool,
+  // custom user profile fields, declared by the host app.
+  #[serde(default)]
+  pub profile_fields: Vec<ProfileFieldDef>,
+  #[serde(default)]
+  pub username_policy: UsernamePolicy,
+  // what users may type in the uid field to log in or reset their password.
+  #[serde(default)]
+  pub login_identifier: LoginIdentifier,
+  // how long a user's previous name stays reserved after a rename.  None for forever.
+  #[serde(default)]
+  pub username_reservation_ms: Option<i64>,
+  // deleted users can be restored for this long before they're purged.
+  // None to delete users immediately.  Also applies to users deleting their own accounts.
+  #[serde(default)]
+  pub delete_grace_period_ms: Option<i64>,
+  // lifetime of admin impersonation tokens.  None for the 30 minute default.
+  #[serde(default)]
+  pub impersonation_expiration_ms: Option<i64>,
+  // default number of invites a non-admin user may make, per invite_quota_period_ms
+  // or in total.  None for unlimited.
+  #[serde(default)]
+  pub invite_quota: Option<i64>,
+  #[serde(default)]
+  pub invite_quota_period_ms: Option<i64>,
+  // new self-registered users wait for an admin to approve them.
+  #[serde(default)]
+  pub registration_approval: bool,
+  // email domain rules for registration, RSVP and email changes.  "example.com" is
+  // just that domain, "*.example.com" its subdomains.  An empty allowlist allows all.
+  #[serde(default)]
+  pub email_domain_allowlist: Vec<String>,
+  #[serde(default)]
+  pub email_domain_blocklist: Vec<String>,
+  // more blocked domains, one per line.  Lines starting with # are ignored.  Read
+  // again whenever the file changes.
+  #[serde(default)]
+  pub email_domain_blocklist_file: Option<PathBuf>,
+  // how long the revert link sent to the old address works after an email change.
+  // None for the 7 day default.
+  #[serde(default)]
+  pub email_revert_expiration_ms: Option<i64>,
+  // minimum time between registration emails to the same user.  None for the 5 minute default.
+  #[serde(default)]
+  pub registration_resend_interval_ms: Option<i64>,
+  // None to only verify emails through the registration link, as before.
+  #[serde(default)]
+  pub email_verification: Option<EmailVerification>,
+}
+
+pub const DEFAULT_IMPERSONATION_EXPIRATION_MS: i64 = 30 * 60 * 1000;
+pub const DEFAULT_EMAIL_REVERT_EXPIRATION_MS: i64 = 7 * 24 * 60 * 60 * 1000;
+pub const DEFAULT_REGISTRATION_RESEND_INTERVAL_MS: i64 = 5 * 60 * 1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LoginIdentifier {
+  #[default]
+  Username,
+  Email,
+  Both,
+}
+
+// what unverified users can do.  Verification is only enforced when send_emails is on.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EmailVerification {
+  // no login until the email is verified.
+  BlockLogin,
+  // login is allowed, but not invites, email changes, or org and group management.
+  // Apps can check dbfun::email_restricted or LoginData.email_verified for their own features.
+  Restricted,
+  NotRequired,
+}
+
+// rules for new usernames, applied after normalization.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct UsernamePolicy {
+  pub min_length: i64,
+  pub max_length: i64,
+  // allowed in addition to letters and digits.
+  pub allowed_punctuation: String,
+  pub reserved_names: Vec<String>,
+}
+
+impl Default for UsernamePolicy {
+  fn default() -> Self {
+    UsernamePolicy {
+      min_length: 2,
+      max_length: 32,
+      allowed_punctuation: "_-.".to_string(),
+      reserved_names: [
+        "admin",
+        "administrator",
+        "root",
+        "api",
+        "system",
+        "support",
+        "help",
+        "info",
+        "mail",
+        "www",
+        "null",
+        "undefined",
+        "anonymous",
+        "moderator",
+      ]
+      .iter()
+      .map(|s| s.to_string())
+      .collect(),
+    }
+  }
 }
 
 #[derive(Elm, ElmDecode, ElmEncode, Serialize, Deserialize, Debug, Clone)]
@@ -62,9 +226,17 @@ pub struct LoginData {
   pub uuid: Uuid,
   pub name: String,
   pub email: String,
+  // when the current email was verified, if it has been.
+  pub email_verified: Option<i64>,
   pub admin: bool,
   pub active: bool,
   pub remote_url: Option<String>,
+  pub roles: Vec<String>,
+  pub orgs: Vec<OrgMembership>,
+  // the admin, in the LoginData for an impersonation session.  It belongs to the session,
+  // not the user, so other LoginData leave it None; dbfun::token_impersonator gives it
+  // for a session's token.
+  pub impersonator: Option<UserId>,
   pub data: Option<String>,
 }
 
@@ -74,6 +246,12 @@ pub struct AdminSettings {
   pub send_emails: bool,
   pub non_admin_invite: bool,
   pub remote_registration: bool,
+  pub login_token_expiration_ms: Option<i64>,
+  pub email_token_expiration_ms: i64,
+  pub reset_token_expiration_ms: i64,
+  pub invite_token_expiration_ms: i64,
+  pub email_domain_allowlist: Vec<String>,
+  pub email_domain_blocklist: Vec<String>,
 }
 
 pub fn admin_settings(config: &Config) -> AdminSettings {
@@ -82,6 +260,12 @@ pub fn admin_settings(config: &Config) -> AdminSettings {
     send_emails: config.send_emails,
     non_admin_invite: config.non_admin_invite,
     remote_registration: config.remote_registration,
+    login_token_expiration_ms: config.login_token_expiration_ms,
+    email_token_expiration_ms: config.email_token_expiration_ms,
+    reset_token_expiration_ms: config.reset_token_expiration_ms,
+    invite_token_expiration_ms: config.invite_token_expiration_ms,
+    email_domain_allowlist: config.email_domain_allowlist.clone(),
+    email_domain_blocklist: config.email_domain_blocklist.clone(),
   }
 }
 
@@ -100,6 +284,190 @@ pub struct User {
   pub cookie: Option<String>,
 }
 
+// admin view of a user, with account activity.
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct AdminUser {
+  pub login_data: LoginData,
+  pub createdate: i64,
+  // who invited the user, if anyone.
+  pub creator: Option<UserId>,
+  pub last_login: Option<i64>,
+  // last token use.
+  pub last_seen: Option<i64>,
+  pub login_count: i64,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Copy, Deserialize, Serialize, Debug)]
+pub enum UserSort {
+  UsoName,
+  UsoCreateDate,
+  UsoLastLogin,
+  UsoLastSeen,
+  UsoLoginCount,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct UserQuery {
+  // matches anywhere in name or email.
+  pub search: Option<String>,
+  pub admin: Option<bool>,
+  pub active: Option<bool>,
+  pub unregistered: Option<bool>,
+  pub phantom: Option<bool>,
+  // users not seen since this date, including those never seen.
+  pub not_seen_since: Option<i64>,
+  pub created_after: Option<i64>,
+  pub created_before: Option<i64>,
+  pub sort: UserSort,
+  pub descending: bool,
+  // next_cursor from the previous page.
+  pub cursor: Option<String>,
+  pub limit: i64,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct UserPage {
+  pub users: Vec<AdminUser>,
+  // all matching users, not just this page.
+  pub total: i64,
+  pub next_cursor: Option<String>,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct AuditEvent {
+  pub id: i64,
+  // who did it.
+  pub actor: Option<UserId>,
+  // who it was done to.
+  pub target: Option<UserId>,
+  pub event: String,
+  pub detail: Option<String>,
+  pub createdate: i64,
+}
+
+// events with user as actor or target, newest first, older than before.
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct AuditQuery {
+  pub user: Option<UserId>,
+  pub before: Option<i64>,
+  pub limit: i64,
+}
+
+// a soft deleted user, waiting to be purged.
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct DeletedUser {
+  pub userid: UserId,
+  pub name: String,
+  pub email: String,
+  pub deleted_date: i64,
+}
+
+// a self-registered user waiting for admin approval.
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct PendingRegistration {
+  pub userid: UserId,
+  pub name: String,
+  pub email: String,
+  pub createdate: i64,
+  // false until they've clicked the registration email link.
+  pub email_confirmed: bool,
+}
+
+// message is included in the email to the applicant.
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct RegistrationDecision {
+  pub userid: UserId,
+  pub message: Option<String>,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct Suspension {
+  pub userid: UserId,
+  pub reason: String,
+  // reactivates automatically after this date.  None for indefinite.
+  pub until: Option<i64>,
+  pub suspended_by: Option<UserId>,
+  pub createdate: i64,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct SuspendUser {
+  pub userid: UserId,
+  pub reason: String,
+  pub until: Option<i64>,
+}
+
+// what a suspended user is told at login.
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct SuspensionNotice {
+  pub reason: String,
+  pub until: Option<i64>,
+}
+
+// everything orgauth stores about a user, for AthExportData and ArqExportUser.
+// Not an elm type; it goes to the client as a json string.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserDataExport {
+  pub user: ExportUser,
+  pub sessions: Vec<ExportSession>,
+  pub invites: Vec<ExportInvite>,
+  pub email_changes: Vec<ExportEmailChange>,
+  pub name_history: Vec<ExportNameChange>,
+  pub roles: Vec<String>,
+  pub orgs: Vec<OrgMembership>,
+  pub groups: Vec<Group>,
+  pub profile: UserProfile,
+  pub suspension: Option<Suspension>,
+  pub audit_events: Vec<AuditEvent>,
+  // from the Callbacks export_user_data fn.
+  pub app_data: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportUser {
+  pub id: UserId,
+  pub uuid: Uuid,
+  pub name: String,
+  pub email: String,
+  pub registered: bool,
+  pub admin: bool,
+  pub active: bool,
+  pub remote_url: Option<String>,
+  pub createdate: i64,
+  pub creator: Option<UserId>,
+  pub last_login: Option<i64>,
+  pub last_seen: Option<i64>,
+  pub login_count: i64,
+  pub deleted_date: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportSession {
+  pub tokendate: i64,
+  pub regendate: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportInvite {
+  pub email: Option<String>,
+  pub tokendate: i64,
+  pub org: Option<OrgId>,
+  pub data: Option<String>,
+}
+
+// pending email changes.  Completed changes aren't kept.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportEmailChange {
+  pub email: String,
+  pub tokendate: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ExportNameChange {
+  pub name: String,
+  pub changedate: i64,
+}
+
 // Represents a remote user that is not registered on this server.
 #[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
 pub struct PhantomUser {
@@ -117,12 +485,349 @@ pub struct UserInvite {
   pub url: String,
   pub data: Option<String>,
   pub creator: UserId,
+  pub org: Option<OrgId>,
 }
 
+// when org is set, the new user joins that org on RSVP.
 #[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
 pub struct GetInvite {
   pub email: Option<String>,
   pub data: Option<String>,
+  pub org: Option<OrgId>,
+  // None for the invite_token_expiration_ms default.
+  pub expiration_ms: Option<i64>,
+  // None for a single use invite.
+  pub max_uses: Option<i64>,
+  // email the invite link to 'email', with the inviter's message.
+  pub send_email: bool,
+  pub message: Option<String>,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct InviteUse {
+  pub token: String,
+  pub userid: UserId,
+  pub name: String,
+  pub usedate: i64,
+}
+
+// an outstanding invite, for listing.
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct InviteInfo {
+  pub token: String,
+  pub url: String,
+  pub email: Option<String>,
+  pub creator: UserId,
+  pub creator_name: String,
+  pub data: Option<String>,
+  pub org: Option<OrgId>,
+  pub createdate: i64,
+  pub expiredate: i64,
+  pub max_uses: i64,
+  pub uses: Vec<InviteUse>,
+}
+
+// a user's invite allowance.  quota None for unlimited, period_ms None for a lifetime quota.
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct InviteQuota {
+  pub userid: UserId,
+  pub quota: Option<i64>,
+  pub period_ms: Option<i64>,
+  pub revoked: bool,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct InviteQuotaStatus {
+  pub quota: InviteQuota,
+  // invite uses issued in the current period; an invite counts as its max_uses.
+  pub used: i64,
+  pub remaining: Option<i64>,
+}
+
+// a node in the referral tree, built from user creators.
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct Referral {
+  pub userid: UserId,
+  pub name: String,
+  pub creator: Option<UserId>,
+  pub active: bool,
+  // users this one brought in directly.
+  pub invited: i64,
+  // everyone below this user in the tree.
+  pub total: i64,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Copy, Deserialize, Serialize, Debug)]
+pub enum NewUserPassword {
+  // generated, and returned once in CreatedUser.
+  NupTemporary,
+  // the user gets an email link to set their password.
+  NupEmailLink,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct CreateUser {
+  pub name: String,
+  pub email: String,
+  pub admin: bool,
+  pub data: Option<String>,
+  pub password: NewUserPassword,
+  // login is refused until the user picks a new password.
+  pub require_password_change: bool,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct CreatedUser {
+  pub login_data: LoginData,
+  pub temp_password: Option<String>,
+  pub reset_url: Option<String>,
+}
+
+// log in with the current password and change it at the same time, for
+// UrpPasswordChangeRequired.
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct ChangePasswordLogin {
+  pub uid: String,
+  pub oldpwd: String,
+  pub newpwd: String,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct ImportUser {
+  pub name: String,
+  pub email: String,
+  pub admin: bool,
+  pub data: Option<String>,
+}
+
+// csv is one user per line: name,email,admin,data.  A header line is optional.
+// json is an array of ImportUser.
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub enum ImportSource {
+  IsCsv(String),
+  IsJson(String),
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Copy, Deserialize, Serialize, Debug)]
+pub enum ImportMode {
+  // create accounts, and email each user a link to set their password.
+  ImCreate,
+  // make an invite for each user's email.
+  ImInvite,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct ImportUsers {
+  pub source: ImportSource,
+  pub mode: ImportMode,
+  // validate only; nothing is written.
+  pub dry_run: bool,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub enum ImportOutcome {
+  IoValid,
+  IoCreated(UserId),
+  IoInvited(UserInvite),
+  IoInvalidUserName(UsernameError),
+  IoEmailInUse,
+  IoError(String),
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct ImportRow {
+  // 1 based, counting the csv header if any.
+  pub row: i64,
+  pub name: String,
+  pub outcome: ImportOutcome,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Copy, Deserialize, Serialize, Debug)]
+pub enum BulkOp {
+  BopDeactivate,
+  BopActivate,
+  BopDelete,
+  BopLogout,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct BulkUserOp {
+  pub op: BulkOp,
+  pub users: Vec<UserId>,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub enum BulkOutcome {
+  BorDone,
+  BorNotFound,
+  // on_delete_user declined.
+  BorNotDeleted,
+  BorError(String),
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct BulkResult {
+  pub userid: UserId,
+  pub outcome: BulkOutcome,
+}
+
+// A named set of permissions.  The built-in "admin" role grants every permission.
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct Role {
+  pub name: String,
+  pub description: Option<String>,
+  pub permissions: Vec<String>,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct UserRole {
+  pub userid: UserId,
+  pub role: String,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Debug)]
+pub enum OrgRole {
+  OrOwner,
+  OrAdmin,
+  OrMember,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct Org {
+  pub id: OrgId,
+  pub name: String,
+}
+
+// an org the logged in user belongs to.
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct OrgMembership {
+  pub org: OrgId,
+  pub name: String,
+  pub role: OrgRole,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct OrgMember {
+  pub userid: UserId,
+  pub name: String,
+  pub role: OrgRole,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct OrgMembers {
+  pub org: OrgId,
+  pub members: Vec<OrgMember>,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct OrgUser {
+  pub org: OrgId,
+  pub userid: UserId,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct SetOrgMemberRole {
+  pub org: OrgId,
+  pub userid: UserId,
+  pub role: OrgRole,
+}
+
+// owner is None if the owning user was deleted.
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct Group {
+  pub id: GroupId,
+  pub name: String,
+  pub owner: Option<UserId>,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct GroupMember {
+  pub userid: UserId,
+  pub name: String,
+}
+
+// direct members and subgroups only; see dbfun::read_group_users for the nested set.
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct GroupInfo {
+  pub group: Group,
+  pub members: Vec<GroupMember>,
+  pub subgroups: Vec<Group>,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct RenameGroup {
+  pub group: GroupId,
+  pub name: String,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct GroupUser {
+  pub group: GroupId,
+  pub userid: UserId,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct Subgroup {
+  pub group: GroupId,
+  pub subgroup: GroupId,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct UserGroups {
+  pub userid: UserId,
+  pub groups: Vec<Group>,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Copy, Deserialize, Serialize, PartialEq, Eq, Debug)]
+pub enum ProfileFieldType {
+  PftText,
+  PftInteger,
+  PftBool,
+  PftUrl,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct ProfileFieldDef {
+  pub name: String,
+  pub field_type: ProfileFieldType,
+  pub required: bool,
+  pub max_length: Option<i64>,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct ProfileFieldValue {
+  pub name: String,
+  pub value: String,
+}
+
+// avatar is an http(s) url, or a small data: url image.
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct UserProfile {
+  pub userid: UserId,
+  pub display_name: Option<String>,
+  pub avatar: Option<String>,
+  pub locale: Option<String>,
+  pub timezone: Option<String>,
+  pub bio: Option<String>,
+  pub custom: Vec<ProfileFieldValue>,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, Debug)]
+pub struct ProfileFieldError {
+  pub field: String,
+  pub message: String,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Clone, Deserialize, Serialize, PartialEq, Eq, Debug)]
+pub enum UsernameError {
+  UneBlank,
+  UneTooShort(i64),
+  UneTooLong(i64),
+  UneInvalidCharacter(String),
+  UneInvalidStart,
+  UneReserved,
+  UneTaken,
+  UneConfusable,
 }
 
 #[derive(Elm, ElmDecode, ElmEncode, Serialize, Deserialize, Debug)]
@@ -141,17 +846,27 @@ pub struct RSVP {
   pub invite: String,
 }
 
+// uid is a username or email, depending on Config.login_identifier.
 #[derive(Elm, ElmDecode, ElmEncode, Serialize, Deserialize, Debug)]
 pub struct Login {
   pub uid: String,
   pub pwd: String,
 }
 
+// uid is a username or email, depending on Config.login_identifier.
 #[derive(Elm, ElmDecode, ElmEncode, Serialize, Deserialize, Debug)]
 pub struct ResetPassword {
   pub uid: String,
 }
 
+// uid as in ResetPassword.  new_key replaces the registration key, so older
+// registration links stop working.
+#[derive(Elm, ElmDecode, ElmEncode, Serialize, Deserialize, Debug)]
+pub struct ResendRegistration {
+  pub uid: String,
+  pub new_key: bool,
+}
+
 #[derive(Elm, ElmDecode, ElmEncode, Serialize, Deserialize, Debug)]
 pub struct PwdReset {
   pub userid: UserId,
@@ -177,6 +892,25 @@ pub struct ChangeEmail {
   pub email: String,
 }
 
+// an email change waiting for confirmation from the new address.
+#[derive(Elm, ElmDecode, ElmEncode, Serialize, Deserialize, Debug, Clone)]
+pub struct PendingEmailChange {
+  pub email: String,
+  pub tokendate: i64,
+  pub expiredate: i64,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Serialize, Deserialize, Debug, Clone)]
+pub struct ChangeUserName {
+  pub pwd: String,
+  pub name: String,
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Serialize, Deserialize, Debug, Clone)]
+pub struct DeleteAccount {
+  pub pwd: String,
+}
+
 #[derive(Elm, ElmDecode, ElmEncode, Serialize, Deserialize, Debug, Clone)]
 pub struct ChangeRemoteUrl {
   pub pwd: String,
@@ -192,6 +926,12 @@ pub enum UserRequest {
   UrqResetPassword(ResetPassword),
   UrqSetPassword(SetPassword),
   UrqLogout,
+  // back to the admin's own session.
+  UrqEndImpersonation,
+  UrqChangePasswordLogin(ChangePasswordLogin),
+  UrqResendRegistration(ResendRegistration),
+  // uid as in ResetPassword.  For users who can't log in until they verify.
+  UrqSendEmailVerification(String),
   UrqAuthedRequest(AuthedRequest),
 }
 
@@ -202,6 +942,56 @@ pub enum AuthedRequest {
   AthChangeEmail(ChangeEmail),
   AthChangeRemoteUrl(ChangeRemoteUrl),
   AthReadRemoteUser(UserId),
+  AthChangeUserName(ChangeUserName),
+  AthCreateOrg(String),
+  AthLeaveOrg(OrgId),
+  AthOrgRequest(OrgRequest),
+  AthGroupRequest(GroupRequest),
+  AthGetProfile,
+  AthUpdateProfile(UserProfile),
+  AthGetProfileFields,
+  AthDeleteAccount(DeleteAccount),
+  AthExportData,
+  AthGetInvites,
+  AthRevokeInvite(String),
+  AthGetInviteQuota,
+  AthGetPendingEmailChanges,
+  // cancel the pending change to this email.
+  AthCancelEmailChange(String),
+  AthSendEmailVerification,
+}
+
+// org-scoped admin requests; allowed for org owners and admins, and global admins.
+#[derive(Elm, ElmDecode, ElmEncode, Serialize, Deserialize, Debug)]
+pub enum OrgRequest {
+  OrqGetMembers(OrgId),
+  OrqSetMemberRole(SetOrgMemberRole),
+  OrqRemoveMember(OrgUser),
+  OrqTransferOwnership(OrgUser),
+}
+
+// used by group owners through AthGroupRequest, and admins through ArqGroupRequest.
+#[derive(Elm, ElmDecode, ElmEncode, Serialize, Deserialize, Debug)]
+pub enum GroupRequest {
+  GrqCreateGroup(String),
+  GrqRenameGroup(RenameGroup),
+  GrqDeleteGroup(GroupId),
+  GrqAddMember(GroupUser),
+  GrqRemoveMember(GroupUser),
+  GrqAddSubgroup(Subgroup),
+  GrqRemoveSubgroup(Subgroup),
+  GrqGetGroup(GroupId),
+  GrqGetGroups,
+  GrqGetUserGroups(UserId),
+}
+
+#[derive(Elm, ElmDecode, ElmEncode, Deserialize, Serialize, Debug)]
+pub enum GroupResponse {
+  GrpGroup(GroupInfo),
+  GrpGroups(Vec<Group>),
+  GrpGroupDeleted(GroupId),
+  GrpUserGroups(UserGroups),
+  GrpAccessDenied,
 }
 
 #[derive(Elm, ElmDecode, ElmEncode, Deserialize, Serialize, Debug)]
@@ -209,6 +999,9 @@ pub enum UserResponse {
   UrpRegistrationSent,
   UrpUserExists,
   UrpUnregisteredUser,
+  UrpRegistrationPending,
+  // registration emails were sent too recently; try again after this time.
+  UrpRegistrationResendTooSoon(i64),
   UrpInvalidUserOrPwd,
   UrpInvalidUserId,
   UrpInvalidUserUuid,
@@ -216,16 +1009,51 @@ pub enum UserResponse {
   UrpBlankPassword,
   UrpNotLoggedIn,
   UrpAccountDeactivated,
+  UrpAccountSuspended(SuspensionNotice),
   UrpLoggedIn(LoginData),
   UrpLoggedOut,
   UrpChangedPassword,
   UrpChangedEmail,
   UrpChangedRemoteUrl(String),
+  UrpChangedUserName(String),
   UrpResetPasswordAck,
   UrpSetPasswordAck,
   UrpInvite(UserInvite),
   UrpRemoteRegistrationFailed,
   UrpRemoteUser(PhantomUser),
+  UrpOrg(Org),
+  UrpOrgMembers(OrgMembers),
+  UrpOrgMemberRemoved(OrgUser),
+  UrpLeftOrg(OrgId),
+  UrpOrgAccessDenied,
+  UrpGroupResponse(GroupResponse),
+  UrpProfile(UserProfile),
+  UrpProfileFields(Vec<ProfileFieldDef>),
+  UrpInvalidProfile(Vec<ProfileFieldError>),
+  UrpInvalidUserName(UsernameError),
+  UrpEmailInUse,
+  UrpEmailDomainNotAllowed,
+  UrpEmailDomainBlocked,
+  UrpEmailNotVerified,
+  UrpEmailAlreadyVerified,
+  UrpEmailVerificationSent,
+  // a verification email was sent too recently; try again after this time.
+  UrpEmailVerificationTooSoon(i64),
+  UrpInviteEmailMismatch,
+  UrpAccountDeleted,
+  UrpAccountDeletionScheduled(i64),
+  UrpAccountNotDeleted,
+  UrpLastAdmin,
+  UrpImpersonationDenied,
+  UrpPasswordChangeRequired,
+  UrpInvites(Vec<InviteInfo>),
+  UrpInviteRevoked(String),
+  UrpInviteQuota(InviteQuotaStatus),
+  UrpInviteQuotaExceeded,
+  UrpInvitesRevoked,
+  UrpPendingEmailChanges(Vec<PendingEmailChange>),
+  UrpEmailChangeCancelled(String),
+  UrpDataExport(String),
   UrpNoData, // TODO: remove?
   UrpServerError(String),
 }
@@ -237,6 +1065,47 @@ pub enum AdminRequest {
   ArqUpdateUser(LoginData),
   ArqGetInvite(GetInvite),
   ArqGetPwdReset(UserId),
+  ArqGetRoles,
+  ArqSaveRole(Role),
+  ArqDeleteRole(String),
+  ArqAssignRole(UserRole),
+  ArqRevokeRole(UserRole),
+  ArqGetOrgs,
+  ArqGroupRequest(GroupRequest),
+  ArqGetProfile(UserId),
+  ArqUpdateProfile(UserProfile),
+  ArqGetDeletedUsers,
+  ArqRestoreUser(UserId),
+  ArqExportUser(UserId),
+  ArqSuspendUser(SuspendUser),
+  ArqLiftSuspension(UserId),
+  ArqGetSuspensions,
+  ArqQueryUsers(UserQuery),
+  ArqImportUsers(ImportUsers),
+  ArqBulkUserOp(BulkUserOp),
+  ArqImpersonate(UserId),
+  ArqGetAuditLog(AuditQuery),
+  ArqCreateUser(CreateUser),
+  ArqGetSettings,
+  ArqUpdateSettings(AdminSettings),
+  // back to the Config file values.
+  ArqResetSettings,
+  ArqGetInvites,
+  ArqRevokeInvite(String),
+  ArqGetInviteUses,
+  ArqGetInviteQuota(UserId),
+  ArqSetInviteQuota(InviteQuota),
+  // the subtree under a user, or the whole tree.
+  ArqGetReferrals(Option<UserId>),
+  // deactivate a user and everyone they brought in, and revoke their invites.
+  ArqDisableSubtree(UserId),
+  ArqGetPendingRegistrations,
+  ArqApproveRegistration(RegistrationDecision),
+  // deletes the user.
+  ArqRejectRegistration(RegistrationDecision),
+  ArqGetRegistrationLink(UserId),
+  // complete registration without the email link.
+  ArqMarkRegistered(UserId),
 }
 
 #[derive(Elm, ElmDecode, ElmEncode, Deserialize, Serialize, Debug)]
@@ -253,4 +1122,40 @@ pub enum AdminResponse {
   ArpNotLoggedIn,
   ArpInvalidUserOrPassword,
   ArpAccessDenied,
+  ArpRoles(Vec<Role>),
+  ArpRoleSaved(Role),
+  ArpRoleDeleted(String),
+  ArpRoleAssigned(UserRole),
+  ArpRoleRevoked(UserRole),
+  ArpOrgs(Vec<Org>),
+  ArpGroupResponse(GroupResponse),
+  ArpProfile(UserProfile),
+  ArpInvalidProfile(Vec<ProfileFieldError>),
+  ArpInvalidUserName(UsernameError),
+  ArpDeletedUsers(Vec<DeletedUser>),
+  ArpUserRestored(LoginData),
+  ArpUserExport(String),
+  ArpUserSuspended(Suspension),
+  ArpSuspensionLifted(UserId),
+  ArpSuspensions(Vec<Suspension>),
+  ArpUserPage(UserPage),
+  ArpImportResult(Vec<ImportRow>),
+  ArpBulkResult(Vec<BulkResult>),
+  ArpImpersonating(LoginData),
+  ArpAuditLog(Vec<AuditEvent>),
+  ArpUserCreated(CreatedUser),
+  ArpEmailInUse,
+  ArpSettings(AdminSettings),
+  ArpInvalidSettings(String),
+  ArpInvites(Vec<InviteInfo>),
+  ArpInviteRevoked(String),
+  ArpInviteUses(Vec<InviteUse>),
+  ArpInviteQuota(InviteQuotaStatus),
+  ArpReferrals(Vec<Referral>),
+  ArpSubtreeDisabled(Vec<UserId>),
+  ArpPendingRegistrations(Vec<PendingRegistration>),
+  ArpRegistrationApproved(UserId),
+  ArpRegistrationRejected(UserId),
+  ArpRegistrationLink(String),
+  ArpUserRegistered(UserId),
 }
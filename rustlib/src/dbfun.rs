@@ -1,15 +1,25 @@
 use crate::data::{
-  ChangeEmail, ChangePassword, ChangeRemoteUrl, Login, LoginData, User, UserId, UserInvite,
-  UserRequest, UserResponse,
+  AdminSettings, AdminUser, AuditEvent, AuditQuery, ChangeEmail, ChangePassword, ChangeRemoteUrl,
+  ChangeUserName, DeletedUser, ExportEmailChange, ExportInvite, ExportNameChange, ExportSession,
+  ExportUser, Group, GroupId, GroupInfo, GroupMember, InviteInfo, InviteQuota, InviteQuotaStatus,
+  InviteUse, Login, LoginData, LoginIdentifier, Org, OrgId, OrgMember, OrgMembership, OrgRole,
+  PendingEmailChange, PendingRegistration, ProfileFieldDef, ProfileFieldError, ProfileFieldType,
+  ProfileFieldValue, Referral, Role, SuspendUser, Suspension, User, UserDataExport, UserId,
+  UserInvite, UserPage, UserProfile, UserQuery, UserRequest, UserResponse, UserRole, UserSort,
+  UsernameError,
+};
+use crate::data::{
+  Config, EmailVerification, RegistrationData, DEFAULT_EMAIL_REVERT_EXPIRATION_MS,
 };
-use crate::data::{Config, RegistrationData};
 use crate::error;
-use crate::util::{is_token_expired, now, salt_string};
+use crate::username;
+use crate::util::{is_token_expired, normalize_email, now, salt_string, valid_domain_pattern};
 use actix_session::Session;
 use log::{error, info, warn};
 use rusqlite::{params, Connection};
 use sha256;
 use simple_error::bail;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::path::Path;
 use std::time::Duration;
 use uuid::Uuid;
@@ -32,6 +42,9 @@ pub fn connection_open(dbfile: &Path) -> Result<Connection, error::Error> {
 
 const REGEN_MS: i64 = 10 * 1000;
 
+// built-in role, kept in sync with the orgauth_user.admin flag.
+pub const ADMIN_ROLE: &str = "admin";
+
 pub fn new_user(
   conn: &Connection,
   rd: &RegistrationData,
@@ -71,18 +84,27 @@ pub fn new_user(
 
   // make a user record.
   conn.execute(
-    "insert into orgauth_user (name, uuid, hashwd, salt, email, admin, active, registration_key, remote_url, cookie, createdate)
-      values (?1, ?2, ?3, ?4, ?5, ?6, 1, ?7, ?8, ?9, ?10)",
-    params![rd.uid.to_lowercase(), uuid.to_string(), hashwd, salt, rd.email, admin, registration_key, remote_url, cookie, now],
+    "insert into orgauth_user (name, name_skeleton, uuid, hashwd, salt, email, email_normalized, admin, active, registration_key, remote_url, cookie, createdate, creator)
+      values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, 1, ?9, ?10, ?11, ?12, ?13)",
+    params![username::normalize(rd.uid.as_str()), username::name_skeleton(rd.uid.as_str()), uuid.to_string(), hashwd, salt, rd.email, normalize_email(rd.email.as_str()), admin, registration_key, remote_url, cookie, now, creator.map(|c| *c.to_i64())],
   )?;
 
   let uid = UserId::Uid(conn.last_insert_rowid());
 
+  if admin {
+    set_admin_role(conn, uid, true)?;
+  }
+
   (on_new_user)(&conn, &rd, data, remote_data, creator, uid)?;
 
   Ok(uid)
 }
 
+// phantom users stand in for remote users; they never register.
+pub fn is_phantom(user: &User) -> bool {
+  user.registration_key.as_deref() == Some("phantom")
+}
+
 pub fn phantom_user(
   conn: &Connection,
   name: &String,
@@ -102,7 +124,7 @@ pub fn phantom_user(
 ) -> Result<UserId, error::Error> {
   let now = now()?;
   let rd = RegistrationData {
-    uid: name.to_lowercase(),
+    uid: username::normalize(name),
     pwd: "".to_string(),
     email: "".to_string(),
     remote_url: "".to_string(),
@@ -110,9 +132,9 @@ pub fn phantom_user(
 
   // make a user record.
   conn.execute(
-    "insert into orgauth_user (name, uuid, hashwd, salt, email, admin, active, registration_key, createdate)
-      values (?1, ?2, ?3, ?4, ?5, 0, ?6, ?7, ?8)",
-    params![name.to_lowercase(), uuid.to_string(), "phantom", "phantom", "phantom", active,"phantom", now],
+    "insert into orgauth_user (name, name_skeleton, uuid, hashwd, salt, email, admin, active, registration_key, createdate)
+      values (?1, ?2, ?3, ?4, ?5, ?6, 0, ?7, ?8, ?9)",
+    params![username::normalize(name), username::name_skeleton(name), uuid.to_string(), "phantom", "phantom", "phantom", active,"phantom", now],
   )?;
 
   let uid = UserId::Uid(conn.last_insert_rowid());
@@ -128,12 +150,327 @@ pub fn user_id(conn: &Connection, name: &str) -> Result<UserId, error::Error> {
   let id: i64 = conn.query_row(
     "select id from orgauth_user
       where orgauth_user.name = ?1",
-    params![name.to_lowercase()],
+    params![username::normalize(name)],
     |row| Ok(row.get(0)?),
   )?;
   Ok(UserId::Uid(id))
 }
 
+// full username policy check for a new or changed name, including whether it's
+// taken, reserved by a recent rename, or confusable with another user's name.
+// exclude is the user being renamed.
+pub fn check_username(
+  conn: &Connection,
+  config: &Config,
+  name: &str,
+  exclude: Option<UserId>,
+) -> Result<Result<(), UsernameError>, error::Error> {
+  let name = username::normalize(name);
+  if let Err(e) = username::validate(&config.username_policy, name.as_str()) {
+    return Ok(Err(e));
+  }
+  let exclude = exclude.map(|uid| *uid.to_i64());
+  let taken: i64 = conn.query_row(
+    "select count(*) from orgauth_user
+      where name = ?1 and (?2 is null or id != ?2)",
+    params![name, exclude],
+    |row| row.get(0),
+  )?;
+  let reserved_since = match config.username_reservation_ms {
+    Some(ms) => now()? - ms,
+    None => 0,
+  };
+  let reserved: i64 = conn.query_row(
+    "select count(*) from orgauth_username_history
+      where name = ?1 and changedate > ?2 and (?3 is null or user != ?3)",
+    params![name, reserved_since, exclude],
+    |row| row.get(0),
+  )?;
+  if taken > 0 || reserved > 0 {
+    return Ok(Err(UsernameError::UneTaken));
+  }
+  let skeleton = username::name_skeleton(name.as_str());
+  let confusable: i64 = conn.query_row(
+    "select (select count(*) from orgauth_user
+        where name_skeleton = ?1 and (?3 is null or id != ?3))
+      + (select count(*) from orgauth_username_history
+        where name_skeleton = ?1 and changedate > ?2 and (?3 is null or user != ?3))",
+    params![skeleton, reserved_since, exclude],
+    |row| row.get(0),
+  )?;
+  if confusable > 0 {
+    Ok(Err(UsernameError::UneConfusable))
+  } else {
+    Ok(Ok(()))
+  }
+}
+
+// rename the user, checking the password first.  The old name goes into
+// orgauth_username_history.  Check the new name with check_username first!
+pub fn change_username(
+  conn: &Connection,
+  uid: UserId,
+  cun: &ChangeUserName,
+) -> Result<String, error::Error> {
+  let mut userdata = read_user_by_id(conn, uid)?;
+  if sha256::digest(
+    (cun.pwd.clone() + userdata.salt.as_str())
+      .into_bytes()
+      .as_slice(),
+  ) != userdata.hashwd
+  {
+    bail!("invalid password!")
+  }
+
+  add_username_history(conn, uid, userdata.name.as_str())?;
+
+  info!("changing username {} to {}", userdata.name, cun.name);
+  userdata.name = username::normalize(cun.name.as_str());
+  update_user(conn, &userdata)?;
+
+  Ok(userdata.name)
+}
+
+pub fn set_must_change_password(
+  conn: &Connection,
+  uid: UserId,
+  must_change: bool,
+) -> Result<(), error::Error> {
+  conn.execute(
+    "update orgauth_user set must_change_password = ?1 where id = ?2",
+    params![must_change, uid.to_i64()],
+  )?;
+  Ok(())
+}
+
+pub fn set_approval_pending(
+  conn: &Connection,
+  uid: UserId,
+  pending: bool,
+) -> Result<(), error::Error> {
+  conn.execute(
+    "update orgauth_user set approval_pending = ?1 where id = ?2",
+    params![pending, uid.to_i64()],
+  )?;
+  Ok(())
+}
+
+pub fn set_email_verified(conn: &Connection, uid: UserId) -> Result<(), error::Error> {
+  conn.execute(
+    "update orgauth_user set email_verified = ?1 where id = ?2",
+    params![now()?, uid.to_i64()],
+  )?;
+  Ok(())
+}
+
+pub fn email_verified(conn: &Connection, uid: UserId) -> Result<Option<i64>, error::Error> {
+  Ok(conn.query_row(
+    "select email_verified from orgauth_user where id = ?1",
+    params![uid.to_i64()],
+    |row| row.get(0),
+  )?)
+}
+
+// under the Restricted policy, an unverified user can log in but can't invite, change
+// email, or create and manage orgs and groups.  Host apps can check this for their own
+// features.
+pub fn email_restricted(
+  conn: &Connection,
+  config: &Config,
+  uid: UserId,
+) -> Result<bool, error::Error> {
+  let config = current_config(conn, config)?;
+  Ok(
+    config.send_emails
+      && config.email_verification == Some(EmailVerification::Restricted)
+      && email_verified(conn, uid)?.is_none(),
+  )
+}
+
+// verification email request, for the user's current email.
+pub fn add_email_verify(
+  conn: &Connection,
+  uid: UserId,
+  email: &str,
+  token: Uuid,
+) -> Result<(), error::Error> {
+  conn.execute(
+    "insert into orgauth_email_verify (user, email, token, tokendate)
+     values (?1, ?2, ?3, ?4)",
+    params![uid.to_i64(), email, token.to_string(), now()?],
+  )?;
+  Ok(())
+}
+
+pub fn last_email_verify(conn: &Connection, uid: UserId) -> Result<Option<i64>, error::Error> {
+  Ok(conn.query_row(
+    "select max(tokendate) from orgauth_email_verify where user = ?1",
+    params![uid.to_i64()],
+    |row| row.get(0),
+  )?)
+}
+
+// mark the email verified if the token is current and was sent to the user's
+// current email.  Returns false otherwise.
+pub fn verify_email(
+  conn: &Connection,
+  uid: UserId,
+  token: Uuid,
+  token_expiration_ms: i64,
+) -> Result<bool, error::Error> {
+  let email: String = match conn.query_row(
+    "select email from orgauth_email_verify
+      where user = ?1 and token = ?2 and tokendate > ?3",
+    params![
+      uid.to_i64(),
+      token.to_string(),
+      now()? - token_expiration_ms
+    ],
+    |row| row.get(0),
+  ) {
+    Ok(e) => e,
+    Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(false),
+    Err(e) => return Err(e.into()),
+  };
+  if read_user_by_id(conn, uid)?.email != email {
+    return Ok(false);
+  }
+  set_email_verified(conn, uid)?;
+  conn.execute(
+    "delete from orgauth_email_verify where user = ?1",
+    params![uid.to_i64()],
+  )?;
+  Ok(true)
+}
+
+pub fn set_registration_sent(conn: &Connection, uid: UserId) -> Result<(), error::Error> {
+  conn.execute(
+    "update orgauth_user set registration_sent = ?1 where id = ?2",
+    params![now()?, uid.to_i64()],
+  )?;
+  Ok(())
+}
+
+pub fn registration_sent(conn: &Connection, uid: UserId) -> Result<Option<i64>, error::Error> {
+  Ok(conn.query_row(
+    "select registration_sent from orgauth_user where id = ?1",
+    params![uid.to_i64()],
+    |row| row.get(0),
+  )?)
+}
+
+pub fn approval_pending(conn: &Connection, uid: UserId) -> Result<bool, error::Error> {
+  Ok(conn.query_row(
+    "select approval_pending from orgauth_user where id = ?1",
+    params![uid.to_i64()],
+    |row| row.get(0),
+  )?)
+}
+
+pub fn read_pending_registrations(
+  conn: &Connection,
+) -> Result<Vec<PendingRegistration>, error::Error> {
+  let mut pstmt = conn.prepare(
+    "select id, name, email, createdate, registration_key is null from orgauth_user
+      where approval_pending and deleted_date is null
+      order by createdate",
+  )?;
+  let r = pstmt
+    .query_map(params![], |row| {
+      Ok(PendingRegistration {
+        userid: UserId::Uid(row.get(0)?),
+        name: row.get(1)?,
+        email: row.get(2)?,
+        createdate: row.get(3)?,
+        email_confirmed: row.get(4)?,
+      })
+    })?
+    .collect::<Result<Vec<PendingRegistration>, rusqlite::Error>>()?;
+  Ok(r)
+}
+
+pub fn must_change_password(conn: &Connection, uid: UserId) -> Result<bool, error::Error> {
+  Ok(conn.query_row(
+    "select must_change_password from orgauth_user where id = ?1",
+    params![uid.to_i64()],
+    |row| row.get(0),
+  )?)
+}
+
+pub fn check_password(conn: &Connection, uid: UserId, pwd: &str) -> Result<bool, error::Error> {
+  let userdata = read_user_by_id(conn, uid)?;
+  Ok(
+    sha256::digest(
+      (pwd.to_string() + userdata.salt.as_str())
+        .into_bytes()
+        .as_slice(),
+    ) == userdata.hashwd,
+  )
+}
+
+// true if uid is an admin and there are no other active admins.
+pub fn is_last_admin(conn: &Connection, uid: UserId) -> Result<bool, error::Error> {
+  let (admin, others): (bool, i64) = conn.query_row(
+    "select admin, (select count(*) from orgauth_user
+        where admin = 1 and active = 1 and deleted_date is null and id != ?1)
+      from orgauth_user where id = ?1",
+    params![uid.to_i64()],
+    |row| Ok((row.get(0)?, row.get(1)?)),
+  )?;
+  Ok(admin && others == 0)
+}
+
+fn add_username_history(conn: &Connection, uid: UserId, oldname: &str) -> Result<(), error::Error> {
+  let now = now()?;
+  conn.execute(
+    "insert into orgauth_username_history (user, name, name_skeleton, changedate)
+      values (?1, ?2, ?3, ?4)",
+    params![uid.to_i64(), oldname, username::name_skeleton(oldname), now],
+  )?;
+  Ok(())
+}
+
+// like read_user_by_name, but falls back to the most recent user to have had
+// the name, so links with an old name still work.
+pub fn resolve_user_by_name(conn: &Connection, name: &str) -> Result<User, error::Error> {
+  if let Some(user) = read_user_by_unnormalized_name(conn, name)? {
+    return Ok(user);
+  }
+  match read_user_by_name(conn, name) {
+    Err(error::Error::Rusqlite(rusqlite::Error::QueryReturnedNoRows)) => {
+      let id: i64 = conn.query_row(
+        "select user from orgauth_username_history
+          where name = ?1 order by changedate desc limit 1",
+        params![username::normalize(name)],
+        |row| row.get(0),
+      )?;
+      read_user_by_id(conn, UserId::Uid(id))
+    }
+    x => x,
+  }
+}
+
+// users renamed by udpate15 keep their old, unnormalized name in the history.
+// Current names are all normalized, so an exact match on one isn't ambiguous.
+fn read_user_by_unnormalized_name(
+  conn: &Connection,
+  name: &str,
+) -> Result<Option<User>, error::Error> {
+  if username::normalize(name) == name {
+    return Ok(None);
+  }
+  match conn.query_row(
+    "select user from orgauth_username_history
+      where name = ?1 order by changedate desc limit 1",
+    params![name],
+    |row| row.get::<usize, i64>(0),
+  ) {
+    Ok(id) => Ok(Some(read_user_by_id(conn, UserId::Uid(id))?)),
+    Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+    Err(e) => Err(e.into()),
+  }
+}
+
 pub fn login_data(conn: &Connection, uid: UserId) -> Result<LoginData, error::Error> {
   let user = read_user_by_id(&conn, uid)?;
   Ok(LoginData {
@@ -141,9 +478,13 @@ pub fn login_data(conn: &Connection, uid: UserId) -> Result<LoginData, error::Er
     uuid: user.uuid,
     name: user.name,
     email: user.email,
+    email_verified: email_verified(conn, uid)?,
     admin: user.admin,
     active: user.active,
     remote_url: user.remote_url,
+    roles: read_user_roles(conn, uid)?,
+    orgs: read_user_orgs(conn, uid)?,
+    impersonator: None,
     data: None,
   })
 }
@@ -161,21 +502,30 @@ pub fn login_data_cb(
     uuid: user.uuid,
     name: user.name,
     email: user.email,
+    email_verified: email_verified(conn, uid)?,
     admin: user.admin,
     active: user.active,
     remote_url: user.remote_url,
+    roles: read_user_roles(conn, uid)?,
+    orgs: read_user_orgs(conn, uid)?,
+    impersonator: None,
     data: extra_login_data(&conn, uid)?.map(|x| x.to_string()),
   })
 }
 
 pub fn update_login_data(conn: &Connection, ld: &LoginData) -> Result<(), error::Error> {
   let mut user = read_user_by_id(&conn, ld.userid)?;
-  user.name = ld.name.to_lowercase();
+  let name = username::normalize(ld.name.as_str());
+  if name != user.name {
+    add_username_history(conn, ld.userid, user.name.as_str())?;
+  }
+  user.name = name;
   user.email = ld.email.clone();
   user.admin = ld.admin;
   user.active = ld.active;
   user.remote_url = ld.remote_url.clone();
-  update_user(&conn, &user)
+  update_user(conn, &user)?;
+  set_admin_role(conn, ld.userid, ld.admin)
 }
 
 pub fn read_users(
@@ -184,10 +534,7 @@ pub fn read_users(
     dyn FnMut(&Connection, UserId) -> Result<Option<serde_json::Value>, error::Error>,
   >,
 ) -> Result<Vec<LoginData>, error::Error> {
-  let mut pstmt = conn.prepare(
-    // return zklinks that link to or from notes that link to 'public'.
-    "select id from orgauth_user",
-  )?;
+  let mut pstmt = conn.prepare("select id from orgauth_user where deleted_date is null")?;
 
   let r = Ok(
     pstmt
@@ -204,16 +551,208 @@ pub fn read_users(
   r
 }
 
+const MAX_USER_PAGE: i64 = 500;
+
+// one page of users for the admin listing.  Users, roles and orgs come from a
+// single query, the total count from a second; the extra_login_data callback is
+// not called, so LoginData.data is None.
+pub fn query_users(conn: &Connection, query: &UserQuery) -> Result<UserPage, error::Error> {
+  // nulls sort as 0, so the cursor key is never null.
+  let sortkey = match query.sort {
+    UserSort::UsoName => "name",
+    UserSort::UsoCreateDate => "createdate",
+    UserSort::UsoLastLogin => "coalesce(last_login, 0)",
+    UserSort::UsoLastSeen => "coalesce(last_seen, 0)",
+    UserSort::UsoLoginCount => "login_count",
+  };
+  let (dir, cmp) = if query.descending {
+    ("desc", "<")
+  } else {
+    ("asc", ">")
+  };
+  let limit = query.limit.clamp(1, MAX_USER_PAGE);
+
+  // cursor is "<id>:<sortkey>" of the last user on the previous page.
+  let (cursor_id, cursor_key) = match &query.cursor {
+    Some(c) => {
+      let bad_cursor = || error::Error::from(format!("invalid cursor: {}", c));
+      let (id, key) = c.split_once(':').ok_or_else(bad_cursor)?;
+      let key = match query.sort {
+        UserSort::UsoName => rusqlite::types::Value::Text(key.to_string()),
+        _ => rusqlite::types::Value::Integer(key.parse().map_err(|_| bad_cursor())?),
+      };
+      (
+        Some(id.parse::<i64>().map_err(|_| bad_cursor())?),
+        Some(key),
+      )
+    }
+    None => (None, None),
+  };
+
+  // phantom users have registration_key 'phantom'; they don't count as unregistered.
+  let filter = "deleted_date is null
+    and (?1 is null or instr(lower(name), lower(?1)) > 0 or instr(lower(email), lower(?1)) > 0)
+    and (?2 is null or admin = ?2)
+    and (?3 is null or active = ?3)
+    and (?4 is null
+      or (registration_key is not null and registration_key != 'phantom') = ?4)
+    and (?5 is null or (registration_key is not null and registration_key = 'phantom') = ?5)
+    and (?6 is null or last_seen is null or last_seen < ?6)
+    and (?7 is null or createdate >= ?7)
+    and (?8 is null or createdate < ?8)";
+
+  // count separately, so an empty page still reports the total.
+  let total: i64 = conn.query_row(
+    format!("select count(*) from orgauth_user where {filter}").as_str(),
+    params![
+      query.search,
+      query.admin,
+      query.active,
+      query.unregistered,
+      query.phantom,
+      query.not_seen_since,
+      query.created_after,
+      query.created_before,
+    ],
+    |row| row.get(0),
+  )?;
+
+  let mut pstmt = conn.prepare(
+    format!(
+      "with matching as (
+        select id, uuid, name, email, admin, active, remote_url, createdate, creator,
+          last_login, last_seen, login_count, {sortkey} as sortkey, email_verified
+        from orgauth_user
+        where {filter})
+      select id, uuid, name, email, admin, active, remote_url, createdate, creator,
+        last_login, last_seen, login_count, sortkey,
+        (select group_concat(orgauth_role.name, char(31))
+          from orgauth_role, orgauth_user_role
+          where orgauth_role.id = orgauth_user_role.role and orgauth_user_role.user = matching.id),
+        (select group_concat(orgauth_org.id || char(31) || orgauth_org.name || char(31) || orgauth_org_member.role, char(30))
+          from orgauth_org, orgauth_org_member
+          where orgauth_org.id = orgauth_org_member.org and orgauth_org_member.user = matching.id),
+        email_verified
+      from matching
+      where ?9 is null or sortkey {cmp} ?9 or (sortkey = ?9 and id {cmp} ?10)
+      order by sortkey {dir}, id {dir}
+      limit ?11"
+    )
+    .as_str(),
+  )?;
+
+  let mut last_key = String::new();
+  let mut more = false;
+  let mut users: Vec<AdminUser> = Vec::new();
+  let mut rows = pstmt.query(params![
+    query.search,
+    query.admin,
+    query.active,
+    query.unregistered,
+    query.phantom,
+    query.not_seen_since,
+    query.created_after,
+    query.created_before,
+    cursor_key,
+    cursor_id,
+    // one extra, to know if there's a next page.
+    limit + 1,
+  ])?;
+  while let Some(row) = rows.next()? {
+    if users.len() as i64 == limit {
+      // the extra row.
+      more = true;
+      break;
+    }
+    let uid = UserId::Uid(row.get(0)?);
+    last_key = match row.get::<usize, rusqlite::types::Value>(12)? {
+      rusqlite::types::Value::Integer(i) => i.to_string(),
+      rusqlite::types::Value::Text(t) => t,
+      _ => String::new(),
+    };
+    let roles = match row.get::<usize, Option<String>>(13)? {
+      Some(r) => r.split('\x1f').map(|s| s.to_string()).collect(),
+      None => Vec::new(),
+    };
+    let mut orgs = Vec::new();
+    if let Some(o) = row.get::<usize, Option<String>>(14)? {
+      for m in o.split('\x1e') {
+        let fields = m.split('\x1f').collect::<Vec<&str>>();
+        if let [id, name, role] = fields[..] {
+          orgs.push(OrgMembership {
+            org: OrgId::Oid(id.parse().map_err(|_| "invalid org id")?),
+            name: name.to_string(),
+            role: org_role_from_str(role)?,
+          });
+        }
+      }
+    }
+    users.push(AdminUser {
+      login_data: LoginData {
+        userid: uid,
+        uuid: Uuid::parse_str(row.get::<usize, String>(1)?.as_str())?,
+        name: row.get(2)?,
+        email: row.get(3)?,
+        email_verified: row.get(15)?,
+        admin: row.get(4)?,
+        active: row.get(5)?,
+        remote_url: row.get(6)?,
+        roles,
+        orgs,
+        impersonator: None,
+        data: None,
+      },
+      createdate: row.get(7)?,
+      creator: row.get::<usize, Option<i64>>(8)?.map(UserId::Uid),
+      last_login: row.get(9)?,
+      last_seen: row.get(10)?,
+      login_count: row.get(11)?,
+    });
+  }
+
+  let next_cursor = match users.last() {
+    Some(u) if more => Some(format!("{}:{}", u.login_data.userid, last_key)),
+    _ => None,
+  };
+  Ok(UserPage {
+    users,
+    total,
+    next_cursor,
+  })
+}
+
+// called on each login.
+pub fn record_login(conn: &Connection, uid: UserId) -> Result<(), error::Error> {
+  let now = now()?;
+  conn.execute(
+    "update orgauth_user set last_login = ?1, last_seen = ?1, login_count = login_count + 1
+      where id = ?2",
+    params![now, uid.to_i64()],
+  )?;
+  Ok(())
+}
+
+// called on token use.  Only writes if last_seen is more than a minute old.
+fn record_seen(conn: &Connection, uid: UserId) -> Result<(), error::Error> {
+  let now = now()?;
+  conn.execute(
+    "update orgauth_user set last_seen = ?1
+      where id = ?2 and (last_seen is null or last_seen < ?3)",
+    params![now, uid.to_i64(), now - 60 * 1000],
+  )?;
+  Ok(())
+}
+
 pub fn read_user_by_name(conn: &Connection, name: &str) -> Result<User, error::Error> {
   let user = conn.query_row_and_then(
     "select id, uuid, hashwd, salt, email, registration_key, admin, active, remote_url, cookie
       from orgauth_user where name = ?1",
-    params![name.to_lowercase()],
+    params![username::normalize(name)],
     |row| {
       Ok::<_, error::Error>(User {
         id: UserId::Uid(row.get(0)?),
         uuid: Uuid::parse_str(row.get::<usize, String>(1)?.as_str())?,
-        name: name.to_lowercase(),
+        name: username::normalize(name),
         hashwd: row.get(2)?,
         salt: row.get(3)?,
         email: row.get(4)?,
@@ -229,6 +768,58 @@ pub fn read_user_by_name(conn: &Connection, name: &str) -> Result<User, error::E
   Ok(user)
 }
 
+pub fn read_user_by_email(conn: &Connection, email: &str) -> Result<User, error::Error> {
+  let id: i64 = conn.query_row(
+    "select id from orgauth_user where email_normalized = ?1",
+    params![normalize_email(email)],
+    |row| row.get(0),
+  )?;
+  read_user_by_id(conn, UserId::Uid(id))
+}
+
+// look up a user by whatever Config.login_identifier allows.
+pub fn read_user_by_login_id(
+  conn: &Connection,
+  login_identifier: LoginIdentifier,
+  uid: &str,
+) -> Result<User, error::Error> {
+  if login_identifier != LoginIdentifier::Email {
+    if let Some(user) = read_user_by_unnormalized_name(conn, uid)? {
+      return Ok(user);
+    }
+  }
+  match login_identifier {
+    LoginIdentifier::Username => read_user_by_name(conn, uid),
+    LoginIdentifier::Email => read_user_by_email(conn, uid),
+    LoginIdentifier::Both => match read_user_by_name(conn, uid) {
+      Err(error::Error::Rusqlite(rusqlite::Error::QueryReturnedNoRows)) => {
+        read_user_by_email(conn, uid)
+      }
+      x => x,
+    },
+  }
+}
+
+// is the email used by a user other than exclude?
+pub fn email_in_use(
+  conn: &Connection,
+  email: &str,
+  exclude: Option<UserId>,
+) -> Result<bool, error::Error> {
+  match normalize_email(email) {
+    None => Ok(false),
+    Some(e) => {
+      let count: i64 = conn.query_row(
+        "select count(*) from orgauth_user
+          where email_normalized = ?1 and (?2 is null or id != ?2)",
+        params![e, exclude.map(|uid| *uid.to_i64())],
+        |row| row.get(0),
+      )?;
+      Ok(count > 0)
+    }
+  }
+}
+
 pub fn read_user_by_id(conn: &Connection, id: UserId) -> Result<User, error::Error> {
   let user = conn.query_row_and_then(
     "select id, uuid, name, hashwd, salt, email, registration_key, admin, active, remote_url, cookie
@@ -283,13 +874,17 @@ struct TokenInfo {
   tokendate: i64,
   regendate: Option<i64>,
   prevtoken: Option<String>,
+  impersonator: Option<i64>,
+  expiredate: Option<i64>,
 }
 
 fn read_user_by_token(conn: &Connection, token: Uuid) -> Result<(User, TokenInfo), error::Error> {
-  let (user, tokendate, regendate, prevtoken) : (User, i64, Option<i64>, Option<String>) = conn.query_row_and_then(
+  let (user, tokendate, regendate, prevtoken, impersonator, expiredate) : (User, i64, Option<i64>, Option<String>, Option<i64>, Option<i64>) = conn.query_row_and_then(
     "select id, uuid, name, hashwd, salt, email, registration_key, admin, active, remote_url, cookie,
-        orgauth_token.tokendate, orgauth_token.regendate, orgauth_token.prevtoken
-      from orgauth_user, orgauth_token where orgauth_user.id = orgauth_token.user and orgauth_token.token = ?1",
+        orgauth_token.tokendate, orgauth_token.regendate, orgauth_token.prevtoken,
+        orgauth_token.impersonator, orgauth_token.expiredate
+      from orgauth_user, orgauth_token where orgauth_user.id = orgauth_token.user and orgauth_token.token = ?1
+        and orgauth_user.deleted_date is null",
     params![token.to_string()],
     |row| {
       Ok::<_, error::Error>((
@@ -309,6 +904,8 @@ fn read_user_by_token(conn: &Connection, token: Uuid) -> Result<(User, TokenInfo
         row.get(11)?,
         row.get(12)?,
         row.get(13)?,
+        row.get(14)?,
+        row.get(15)?,
       ))
     },
   )?;
@@ -319,18 +916,26 @@ fn read_user_by_token(conn: &Connection, token: Uuid) -> Result<(User, TokenInfo
       tokendate,
       regendate,
       prevtoken,
+      impersonator,
+      expiredate,
     },
   ))
 }
 
 fn check_user(
   user: &User,
-  tokendate: i64,
+  tokeninfo: &TokenInfo,
   token_expiration_ms: Option<i64>,
 ) -> Result<(), error::Error> {
+  let tokendate = tokeninfo.tokendate;
   if !user.active {
     Err("account is inactive".into())
   } else {
+    if let Some(exp) = tokeninfo.expiredate {
+      if now()? > exp {
+        return Err("login expired".into());
+      }
+    }
     if let Some(texp) = token_expiration_ms {
       if is_token_expired(texp, tokendate) {
         // Err(error::Error::String("login expired".to_string()))
@@ -352,7 +957,8 @@ pub fn read_user_by_token_api(
 ) -> Result<User, error::Error> {
   let (user, tokeninfo) = read_user_by_token(&conn, token)?;
 
-  check_user(&user, tokeninfo.tokendate, token_expiration_ms)?;
+  check_user(&user, &tokeninfo, token_expiration_ms)?;
+  record_seen(conn, user.id)?;
 
   if regen_login_tokens {
     if let Some(pt) = tokeninfo.prevtoken {
@@ -465,9 +1071,11 @@ fn read_user_with_token_pageload_internal(
 
   let (user, tokeninfo) = read_user_by_token(&tx, token)?;
 
-  check_user(&user, tokeninfo.tokendate, token_expiration_ms)?;
+  check_user(&user, &tokeninfo, token_expiration_ms)?;
+  record_seen(&tx, user.id)?;
 
-  if regen_login_tokens {
+  // impersonation tokens are short lived, and aren't regenerated.
+  if regen_login_tokens && tokeninfo.impersonator.is_none() {
     let nt = match tokeninfo.regendate {
       Some(dt) => {
         let now = now()?;
@@ -494,6 +1102,132 @@ fn read_user_with_token_pageload_internal(
   Ok(user)
 }
 
+pub fn add_impersonation_token(
+  conn: &Connection,
+  user: UserId,
+  token: Uuid,
+  admin: UserId,
+  admin_token: Uuid,
+  expiredate: i64,
+) -> Result<(), error::Error> {
+  let now = now()?;
+  conn.execute(
+    "insert into orgauth_token (user, token, tokendate, impersonator, admin_token, expiredate)
+     values (?1, ?2, ?3, ?4, ?5, ?6)",
+    params![
+      user.to_i64(),
+      token.to_string(),
+      now,
+      admin.to_i64(),
+      admin_token.to_string(),
+      expiredate
+    ],
+  )?;
+
+  Ok(())
+}
+
+// the admin, if token is an impersonation token.
+pub fn token_impersonator(conn: &Connection, token: Uuid) -> Result<Option<UserId>, error::Error> {
+  match conn.query_row(
+    "select impersonator from orgauth_token where token = ?1",
+    params![token.to_string()],
+    |row| row.get::<usize, Option<i64>>(0),
+  ) {
+    Ok(i) => Ok(i.map(UserId::Uid)),
+    Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+    Err(e) => Err(e.into()),
+  }
+}
+
+// remove an impersonation token, returning the impersonated user, the admin,
+// and the admin's own token.
+pub fn end_impersonation(
+  conn: &Connection,
+  token: Uuid,
+) -> Result<Option<(UserId, UserId, Option<Uuid>)>, error::Error> {
+  let r = match conn.query_row(
+    "select user, impersonator, admin_token from orgauth_token
+      where token = ?1 and impersonator is not null",
+    params![token.to_string()],
+    |row| {
+      Ok((
+        UserId::Uid(row.get(0)?),
+        UserId::Uid(row.get(1)?),
+        row.get::<usize, Option<String>>(2)?,
+      ))
+    },
+  ) {
+    Ok(r) => r,
+    Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(None),
+    Err(e) => return Err(e.into()),
+  };
+  conn.execute(
+    "delete from orgauth_token where token = ?1",
+    params![token.to_string()],
+  )?;
+  let admin_token = match r.2 {
+    Some(t) => Some(Uuid::parse_str(t.as_str())?),
+    None => None,
+  };
+  Ok(Some((r.0, r.1, admin_token)))
+}
+
+pub fn add_audit(
+  conn: &Connection,
+  actor: Option<UserId>,
+  target: Option<UserId>,
+  event: &str,
+  detail: Option<&str>,
+) -> Result<(), error::Error> {
+  info!(
+    "audit: {} actor {:?} target {:?} {:?}",
+    event, actor, target, detail
+  );
+  let now = now()?;
+  conn.execute(
+    "insert into orgauth_audit_log (actor, target, event, detail, createdate)
+      values (?1, ?2, ?3, ?4, ?5)",
+    params![
+      actor.map(|u| *u.to_i64()),
+      target.map(|u| *u.to_i64()),
+      event,
+      detail,
+      now
+    ],
+  )?;
+  Ok(())
+}
+
+pub fn read_audit_log(
+  conn: &Connection,
+  query: &AuditQuery,
+) -> Result<Vec<AuditEvent>, error::Error> {
+  let mut pstmt = conn.prepare(
+    "select id, actor, target, event, detail, createdate from orgauth_audit_log
+      where (?1 is null or actor = ?1 or target = ?1)
+        and (?2 is null or createdate < ?2)
+      order by createdate desc, id desc
+      limit ?3",
+  )?;
+  let r = pstmt
+    .query_map(
+      params![query.user.map(|u| *u.to_i64()), query.before, query.limit],
+      |row| {
+        Ok(AuditEvent {
+          id: row.get(0)?,
+          actor: row.get::<usize, Option<i64>>(1)?.map(UserId::Uid),
+          target: row.get::<usize, Option<i64>>(2)?.map(UserId::Uid),
+          event: row.get(3)?,
+          detail: row.get(4)?,
+          createdate: row.get(5)?,
+        })
+      },
+    )?
+    .collect::<Result<Vec<AuditEvent>, rusqlite::Error>>()?;
+  Ok(r)
+}
+
 pub fn add_token(
   conn: &Connection,
   user: UserId,
@@ -595,12 +1329,26 @@ pub fn purge_email_tokens(conn: &Connection, token_expiration_ms: i64) -> Result
   Ok(())
 }
 
-pub fn purge_reset_tokens(conn: &Connection, token_expiration_ms: i64) -> Result<(), error::Error> {
-  let now = now()?;
-  let expdt = now - token_expiration_ms;
-
-  let count: i64 = conn.query_row(
-    "select count(*) from
+pub fn purge_email_reverts(
+  conn: &Connection,
+  revert_expiration_ms: i64,
+) -> Result<(), error::Error> {
+  let count = conn.execute(
+    "delete from orgauth_email_revert where tokendate < ?1",
+    params![now()? - revert_expiration_ms],
+  )?;
+  if count > 0 {
+    info!("removed {} expired orgauth_email_revert records", count);
+  }
+  Ok(())
+}
+
+pub fn purge_reset_tokens(conn: &Connection, token_expiration_ms: i64) -> Result<(), error::Error> {
+  let now = now()?;
+  let expdt = now - token_expiration_ms;
+
+  let count: i64 = conn.query_row(
+    "select count(*) from
       orgauth_newpassword where tokendate < ?1",
     params![expdt],
     |row| Ok(row.get(0)?),
@@ -625,8 +1373,8 @@ pub fn purge_user_invites(conn: &Connection, token_expiration_ms: i64) -> Result
 
   let count: i64 = conn.query_row(
     "select count(*) from
-      orgauth_user_invite where tokendate < ?1",
-    params![expdt],
+      orgauth_user_invite where (expiredate is null and tokendate < ?1) or expiredate < ?2",
+    params![expdt, now],
     |row| Ok(row.get(0)?),
   )?;
 
@@ -635,16 +1383,134 @@ pub fn purge_user_invites(conn: &Connection, token_expiration_ms: i64) -> Result
 
     conn.execute(
       "delete from orgauth_user_invite
-        where tokendate < ?1",
-      params![expdt],
+        where (expiredate is null and tokendate < ?1) or expiredate < ?2",
+      params![expdt, now],
+    )?;
+  }
+
+  Ok(())
+}
+
+// the Config with admin overrides from orgauth_settings applied.
+pub fn current_config(conn: &Connection, config: &Config) -> Result<Config, error::Error> {
+  let mut config = config.clone();
+  let mut pstmt = conn.prepare("select name, value from orgauth_settings")?;
+  let settings = pstmt
+    .query_map(params![], |row| {
+      Ok((row.get::<usize, String>(0)?, row.get::<usize, String>(1)?))
+    })?
+    .collect::<Result<Vec<(String, String)>, rusqlite::Error>>()?;
+  for (name, value) in settings {
+    let v = value.as_str();
+    match name.as_str() {
+      "open_registration" => config.open_registration = serde_json::from_str(v)?,
+      "send_emails" => config.send_emails = serde_json::from_str(v)?,
+      "non_admin_invite" => config.non_admin_invite = serde_json::from_str(v)?,
+      "remote_registration" => config.remote_registration = serde_json::from_str(v)?,
+      "login_token_expiration_ms" => config.login_token_expiration_ms = serde_json::from_str(v)?,
+      "email_token_expiration_ms" => config.email_token_expiration_ms = serde_json::from_str(v)?,
+      "reset_token_expiration_ms" => config.reset_token_expiration_ms = serde_json::from_str(v)?,
+      "invite_token_expiration_ms" => config.invite_token_expiration_ms = serde_json::from_str(v)?,
+      "email_domain_allowlist" => config.email_domain_allowlist = serde_json::from_str(v)?,
+      "email_domain_blocklist" => config.email_domain_blocklist = serde_json::from_str(v)?,
+      _ => warn!("unknown setting: {}", name),
+    }
+  }
+  Ok(config)
+}
+
+pub fn validate_settings(settings: &AdminSettings) -> Result<(), String> {
+  if settings.login_token_expiration_ms.map(|e| e <= 0) == Some(true) {
+    return Err("login_token_expiration_ms must be positive".to_string());
+  }
+  for (name, ms) in [
+    (
+      "email_token_expiration_ms",
+      settings.email_token_expiration_ms,
+    ),
+    (
+      "reset_token_expiration_ms",
+      settings.reset_token_expiration_ms,
+    ),
+    (
+      "invite_token_expiration_ms",
+      settings.invite_token_expiration_ms,
+    ),
+  ] {
+    if ms <= 0 {
+      return Err(format!("{} must be positive", name));
+    }
+  }
+  for pattern in settings
+    .email_domain_allowlist
+    .iter()
+    .chain(settings.email_domain_blocklist.iter())
+  {
+    if !valid_domain_pattern(pattern.as_str()) {
+      return Err(format!("invalid email domain: {}", pattern));
+    }
+  }
+  Ok(())
+}
+
+pub fn save_settings(conn: &Connection, settings: &AdminSettings) -> Result<(), error::Error> {
+  let values = [
+    (
+      "open_registration",
+      serde_json::to_string(&settings.open_registration)?,
+    ),
+    ("send_emails", serde_json::to_string(&settings.send_emails)?),
+    (
+      "non_admin_invite",
+      serde_json::to_string(&settings.non_admin_invite)?,
+    ),
+    (
+      "remote_registration",
+      serde_json::to_string(&settings.remote_registration)?,
+    ),
+    (
+      "login_token_expiration_ms",
+      serde_json::to_string(&settings.login_token_expiration_ms)?,
+    ),
+    (
+      "email_token_expiration_ms",
+      serde_json::to_string(&settings.email_token_expiration_ms)?,
+    ),
+    (
+      "reset_token_expiration_ms",
+      serde_json::to_string(&settings.reset_token_expiration_ms)?,
+    ),
+    (
+      "invite_token_expiration_ms",
+      serde_json::to_string(&settings.invite_token_expiration_ms)?,
+    ),
+    (
+      "email_domain_allowlist",
+      serde_json::to_string(&settings.email_domain_allowlist)?,
+    ),
+    (
+      "email_domain_blocklist",
+      serde_json::to_string(&settings.email_domain_blocklist)?,
+    ),
+  ];
+  for (name, value) in values {
+    conn.execute(
+      "insert into orgauth_settings (name, value) values (?1, ?2)
+        on conflict(name) do update set value = ?2",
+      params![name, value],
     )?;
   }
+  Ok(())
+}
 
+pub fn clear_settings(conn: &Connection) -> Result<(), error::Error> {
+  conn.execute("delete from orgauth_settings", params![])?;
   Ok(())
 }
 
 pub fn purge_tokens(config: &Config) -> Result<(), error::Error> {
   let conn = connection_open(config.db.as_path())?;
+  let config = &current_config(&conn, config)?;
 
   if let Some(expms) = config.login_token_expiration_ms {
     purge_login_tokens(&conn, expms)?;
@@ -652,6 +1518,13 @@ pub fn purge_tokens(config: &Config) -> Result<(), error::Error> {
 
   purge_email_tokens(&conn, config.email_token_expiration_ms)?;
 
+  purge_email_reverts(
+    &conn,
+    config
+      .email_revert_expiration_ms
+      .unwrap_or(DEFAULT_EMAIL_REVERT_EXPIRATION_MS),
+  )?;
+
   purge_reset_tokens(&conn, config.reset_token_expiration_ms)?;
 
   purge_user_invites(&conn, config.invite_token_expiration_ms)?;
@@ -669,10 +1542,13 @@ pub fn update_user(conn: &Connection, user: &User) -> Result<(), error::Error> {
        admin = ?6,
        active = ?7,
        remote_url = ?8,
-       cookie = ?9
+       cookie = ?9,
+       name_skeleton = ?11,
+       email_normalized = case when email = ?4 then email_normalized else ?12 end,
+       email_verified = case when email = ?4 then email_verified else null end
      where id = ?10",
     params![
-      user.name.to_lowercase(),
+      username::normalize(user.name.as_str()),
       user.hashwd,
       user.salt,
       user.email,
@@ -682,6 +1558,8 @@ pub fn update_user(conn: &Connection, user: &User) -> Result<(), error::Error> {
       user.remote_url,
       user.cookie,
       user.id.to_i64(),
+      username::name_skeleton(user.name.as_str()),
+      normalize_email(user.email.as_str()),
     ],
   )?;
 
@@ -721,6 +1599,102 @@ pub fn read_newemail(
   Ok(result)
 }
 
+pub fn read_newemails(
+  conn: &Connection,
+  user: UserId,
+  token_expiration_ms: i64,
+) -> Result<Vec<PendingEmailChange>, error::Error> {
+  let mut pstmt = conn.prepare(
+    "select email, tokendate from orgauth_newemail
+      where user = ?1 and tokendate > ?2
+      order by tokendate",
+  )?;
+  let r = pstmt
+    .query_map(
+      params![user.to_i64(), now()? - token_expiration_ms],
+      |row| {
+        let tokendate: i64 = row.get(1)?;
+        Ok(PendingEmailChange {
+          email: row.get(0)?,
+          tokendate,
+          expiredate: tokendate + token_expiration_ms,
+        })
+      },
+    )?
+    .collect::<Result<Vec<PendingEmailChange>, rusqlite::Error>>()?;
+  Ok(r)
+}
+
+// cancel email change requests; all of them with email None.  Returns the number removed.
+pub fn cancel_newemails(
+  conn: &Connection,
+  user: UserId,
+  email: Option<&str>,
+) -> Result<usize, error::Error> {
+  Ok(conn.execute(
+    "delete from orgauth_newemail where user = ?1 and (?2 is null or email = ?2)",
+    params![user.to_i64(), email],
+  )?)
+}
+
+// after an email change, so the old address can undo it.
+pub fn add_email_revert(
+  conn: &Connection,
+  user: UserId,
+  old_email: &str,
+  new_email: &str,
+  token: Uuid,
+) -> Result<(), error::Error> {
+  conn.execute(
+    "insert into orgauth_email_revert (user, old_email, new_email, token, tokendate)
+     values (?1, ?2, ?3, ?4, ?5)",
+    params![
+      user.to_i64(),
+      old_email,
+      new_email,
+      token.to_string(),
+      now()?
+    ],
+  )?;
+  Ok(())
+}
+
+// put the old email back, cancel pending email changes and log the user out everywhere.
+// Returns false if the token is unknown or expired.
+pub fn revert_email(
+  conn: &Connection,
+  user: UserId,
+  token: Uuid,
+  revert_expiration_ms: i64,
+) -> Result<bool, error::Error> {
+  let old_email: String = match conn.query_row(
+    "select old_email from orgauth_email_revert
+      where user = ?1 and token = ?2 and tokendate > ?3",
+    params![
+      user.to_i64(),
+      token.to_string(),
+      now()? - revert_expiration_ms
+    ],
+    |row| row.get(0),
+  ) {
+    Ok(e) => e,
+    Err(rusqlite::Error::QueryReturnedNoRows) => return Ok(false),
+    Err(e) => return Err(e.into()),
+  };
+  let mut userdata = read_user_by_id(conn, user)?;
+  userdata.email = old_email;
+  update_user(conn, &userdata)?;
+  // the revert link went to the old address.
+  set_email_verified(conn, user)?;
+  cancel_newemails(conn, user, None)?;
+  remove_user_tokens(conn, user)?;
+  conn.execute(
+    "delete from orgauth_email_revert where user = ?1",
+    params![user.to_i64()],
+  )?;
+  Ok(true)
+}
+
 // email change request.
 pub fn remove_newemail(conn: &Connection, user: UserId, token: Uuid) -> Result<(), error::Error> {
   conn.execute(
@@ -778,12 +1752,24 @@ pub fn add_userinvite(
   email: Option<String>,
   creator: UserId,
   data: Option<String>,
+  org: Option<OrgId>,
+  expiredate: i64,
+  max_uses: i64,
 ) -> Result<(), error::Error> {
   let now = now()?;
   conn.execute(
-    "insert into orgauth_user_invite (email, token, tokendate, creator, data)
-     values (?1, ?2, ?3, ?4, ?5)",
-    params![email, token.to_string(), now, creator.to_i64(), data],
+    "insert into orgauth_user_invite (email, token, tokendate, creator, data, org, expiredate, max_uses)
+     values (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+    params![
+      email,
+      token.to_string(),
+      now,
+      creator.to_i64(),
+      data,
+      org.map(|o| *o.to_i64()),
+      expiredate,
+      max_uses
+    ],
   )?;
 
   Ok(())
@@ -807,9 +1793,9 @@ pub fn read_userinvite(
   token: &str,
 ) -> Result<Option<UserInvite>, error::Error> {
   match conn.query_row(
-    "select email, tokendate, data, creator from orgauth_user_invite
-     where token = ?1",
-    params![token],
+    "select email, tokendate, data, creator, org from orgauth_user_invite
+     where token = ?1 and (expiredate is null or expiredate > ?2)",
+    params![token, now()?],
     |row| {
       Ok(UserInvite {
         email: row.get(0)?,
@@ -818,6 +1804,7 @@ pub fn read_userinvite(
         url: format!("{}/invite/{}", mainsite, token),
         data: row.get(2)?,
         creator: UserId::Uid(row.get(3)?),
+        org: row.get::<usize, Option<i64>>(4)?.map(OrgId::Oid),
       })
     },
   ) {
@@ -827,6 +1814,334 @@ pub fn read_userinvite(
   }
 }
 
+// mark the invite as having been emailed to its address.
+pub fn set_userinvite_emailed(conn: &Connection, token: &str) -> Result<(), error::Error> {
+  conn.execute(
+    "update orgauth_user_invite set emailed = 1 where token = ?1",
+    params![token],
+  )?;
+  Ok(())
+}
+
+// was the invite link emailed to the invite address?
+pub fn userinvite_emailed(conn: &Connection, token: &str) -> Result<bool, error::Error> {
+  match conn.query_row(
+    "select emailed from orgauth_user_invite where token = ?1",
+    params![token],
+    |row| row.get(0),
+  ) {
+    Err(rusqlite::Error::QueryReturnedNoRows) => Ok(false),
+    Ok(v) => Ok(v),
+    Err(e) => Err(e.into()),
+  }
+}
+
+// record a use of the invite, and remove it once it's used up.
+pub fn use_userinvite(conn: &Connection, token: &str, uid: UserId) -> Result<(), error::Error> {
+  let now = now()?;
+  conn.execute(
+    "insert into orgauth_invite_use (token, user, creator, usedate)
+      select token, ?2, creator, ?3 from orgauth_user_invite where token = ?1",
+    params![token, uid.to_i64(), now],
+  )?;
+  conn.execute(
+    "update orgauth_user_invite set uses = uses + 1 where token = ?1",
+    params![token],
+  )?;
+  conn.execute(
+    "delete from orgauth_user_invite where token = ?1 and uses >= max_uses",
+    params![token],
+  )?;
+  Ok(())
+}
+
+// outstanding invites, all of them or just the creator's.
+pub fn read_userinvites(
+  conn: &Connection,
+  mainsite: &str,
+  invite_token_expiration_ms: i64,
+  creator: Option<UserId>,
+) -> Result<Vec<InviteInfo>, error::Error> {
+  let mut pstmt = conn.prepare(
+    "select orgauth_user_invite.token, orgauth_user_invite.email, orgauth_user_invite.creator,
+        orgauth_user.name, orgauth_user_invite.data, orgauth_user_invite.org,
+        orgauth_user_invite.tokendate, orgauth_user_invite.expiredate, orgauth_user_invite.max_uses
+      from orgauth_user_invite, orgauth_user
+      where orgauth_user.id = orgauth_user_invite.creator
+        and (?1 is null or orgauth_user_invite.creator = ?1)
+      order by orgauth_user_invite.tokendate",
+  )?;
+  let invites = pstmt
+    .query_map(params![creator.map(|c| *c.to_i64())], |row| {
+      let token: String = row.get(0)?;
+      let createdate: i64 = row.get(6)?;
+      Ok(InviteInfo {
+        url: format!("{}/invite/{}", mainsite, token),
+        token,
+        email: row.get(1)?,
+        creator: UserId::Uid(row.get(2)?),
+        creator_name: row.get(3)?,
+        data: row.get(4)?,
+        org: row.get::<usize, Option<i64>>(5)?.map(OrgId::Oid),
+        createdate,
+        expiredate: row
+          .get::<usize, Option<i64>>(7)?
+          .unwrap_or(createdate + invite_token_expiration_ms),
+        max_uses: row.get(8)?,
+        uses: Vec::new(),
+      })
+    })?
+    .collect::<Result<Vec<InviteInfo>, rusqlite::Error>>()?;
+
+  let mut r = Vec::new();
+  for mut invite in invites {
+    invite.uses = read_invite_uses(conn, Some(invite.token.as_str()))?;
+    r.push(invite);
+  }
+  Ok(r)
+}
+
+pub fn read_invite_uses(
+  conn: &Connection,
+  token: Option<&str>,
+) -> Result<Vec<InviteUse>, error::Error> {
+  let mut pstmt = conn.prepare(
+    "select orgauth_invite_use.token, orgauth_invite_use.user, orgauth_user.name,
+        orgauth_invite_use.usedate
+      from orgauth_invite_use, orgauth_user
+      where orgauth_user.id = orgauth_invite_use.user
+        and (?1 is null or orgauth_invite_use.token = ?1)
+      order by orgauth_invite_use.usedate",
+  )?;
+  let r = pstmt
+    .query_map(params![token], |row| {
+      Ok(InviteUse {
+        token: row.get(0)?,
+        userid: UserId::Uid(row.get(1)?),
+        name: row.get(2)?,
+        usedate: row.get(3)?,
+      })
+    })?
+    .collect::<Result<Vec<InviteUse>, rusqlite::Error>>()?;
+  Ok(r)
+}
+
+// revoke an invite; creator None for admins.  Returns false if there was no such invite.
+pub fn revoke_userinvite(
+  conn: &Connection,
+  token: &str,
+  creator: Option<UserId>,
+) -> Result<bool, error::Error> {
+  let count = conn.execute(
+    "delete from orgauth_user_invite where token = ?1 and (?2 is null or creator = ?2)",
+    params![token, creator.map(|c| *c.to_i64())],
+  )?;
+  Ok(count > 0)
+}
+
+pub fn record_invite_issue(
+  conn: &Connection,
+  creator: UserId,
+  uses: i64,
+) -> Result<(), error::Error> {
+  conn.execute(
+    "insert into orgauth_invite_issue (creator, createdate, uses) values (?1, ?2, ?3)",
+    params![creator.to_i64(), now()?, uses],
+  )?;
+  Ok(())
+}
+
+// the user's quota override, or the config defaults, and invites used in the current period.
+pub fn read_invite_quota(
+  conn: &Connection,
+  config: &Config,
+  uid: UserId,
+) -> Result<InviteQuotaStatus, error::Error> {
+  let quota = match conn.query_row(
+    "select quota, period_ms, revoked from orgauth_invite_quota where user = ?1",
+    params![uid.to_i64()],
+    |row| {
+      Ok(InviteQuota {
+        userid: uid,
+        quota: row.get(0)?,
+        period_ms: row.get(1)?,
+        revoked: row.get(2)?,
+      })
+    },
+  ) {
+    Ok(q) => q,
+    Err(rusqlite::Error::QueryReturnedNoRows) => InviteQuota {
+      userid: uid,
+      quota: config.invite_quota,
+      period_ms: config.invite_quota_period_ms,
+      revoked: false,
+    },
+    Err(e) => return Err(e.into()),
+  };
+
+  let since = match quota.period_ms {
+    Some(p) => now()? - p,
+    None => 0,
+  };
+  let used: i64 = conn.query_row(
+    "select coalesce(sum(uses), 0) from orgauth_invite_issue
+      where creator = ?1 and createdate > ?2",
+    params![uid.to_i64(), since],
+    |row| row.get(0),
+  )?;
+
+  Ok(InviteQuotaStatus {
+    remaining: quota.quota.map(|q| std::cmp::max(q - used, 0)),
+    quota,
+    used,
+  })
+}
+
+// revoking also removes the user's outstanding invites.
+pub fn save_invite_quota(conn: &Connection, quota: &InviteQuota) -> Result<(), error::Error> {
+  conn.execute(
+    "insert into orgauth_invite_quota (user, quota, period_ms, revoked)
+      values (?1, ?2, ?3, ?4)
+      on conflict(user) do update set quota = ?2, period_ms = ?3, revoked = ?4",
+    params![
+      quota.userid.to_i64(),
+      quota.quota,
+      quota.period_ms,
+      quota.revoked
+    ],
+  )?;
+  if quota.revoked {
+    conn.execute(
+      "delete from orgauth_user_invite where creator = ?1",
+      params![quota.userid.to_i64()],
+    )?;
+  }
+  Ok(())
+}
+
+// users with invited and total counts, parents before children.  With root, just
+// root and the users below it.
+pub fn read_referrals(
+  conn: &Connection,
+  root: Option<UserId>,
+) -> Result<Vec<Referral>, error::Error> {
+  let mut pstmt = conn.prepare(
+    "select id, name, creator, active from orgauth_user
+      where deleted_date is null
+      order by id",
+  )?;
+  let users = pstmt
+    .query_map(params![], |row| {
+      Ok(Referral {
+        userid: UserId::Uid(row.get(0)?),
+        name: row.get(1)?,
+        creator: row.get::<usize, Option<i64>>(2)?.map(UserId::Uid),
+        active: row.get(3)?,
+        invited: 0,
+        total: 0,
+      })
+    })?
+    .collect::<Result<Vec<Referral>, rusqlite::Error>>()?;
+
+  let ids: HashSet<i64> = users.iter().map(|u| *u.userid.to_i64()).collect();
+  let mut children: HashMap<i64, Vec<usize>> = HashMap::new();
+  let mut roots = Vec::new();
+  for (i, u) in users.iter().enumerate() {
+    match u.creator {
+      // users whose creator is gone are roots too.
+      Some(c) if c != u.userid && ids.contains(c.to_i64()) => {
+        children.entry(*c.to_i64()).or_default().push(i)
+      }
+      _ => roots.push(i),
+    }
+  }
+  let start = match root {
+    Some(r) => match users.iter().position(|u| u.userid == r) {
+      Some(i) => vec![i],
+      None => return Err(format!("user {} not found", r).into()),
+    },
+    None => roots,
+  };
+
+  // breadth first, so parents come before their children.
+  let mut order = Vec::new();
+  let mut visited = HashSet::new();
+  let mut queue = VecDeque::from(start);
+  while let Some(i) = queue.pop_front() {
+    if !visited.insert(i) {
+      continue;
+    }
+    order.push(i);
+    if let Some(kids) = children.get(users[i].userid.to_i64()) {
+      queue.extend(kids.iter().copied());
+    }
+  }
+
+  // totals, children first.
+  let mut totals: HashMap<usize, i64> = HashMap::new();
+  for i in order.iter().rev() {
+    let total = children
+      .get(users[*i].userid.to_i64())
+      .map(|kids| {
+        kids
+          .iter()
+          .map(|k| 1 + totals.get(k).copied().unwrap_or(0))
+          .sum()
+      })
+      .unwrap_or(0);
+    totals.insert(*i, total);
+  }
+
+  Ok(
+    order
+      .iter()
+      .map(|i| Referral {
+        invited: children
+          .get(users[*i].userid.to_i64())
+          .map(|k| k.len() as i64)
+          .unwrap_or(0),
+        total: totals.get(i).copied().unwrap_or(0),
+        ..users[*i].clone()
+      })
+      .collect(),
+  )
+}
+
+// deactivate uid and everyone below it in the referral tree, except admins.  They're
+// logged out and lose their invite privileges.
+pub fn disable_subtree(
+  conn: &Connection,
+  config: &Config,
+  uid: UserId,
+) -> Result<Vec<UserId>, error::Error> {
+  let mut disabled = Vec::new();
+  for r in read_referrals(conn, Some(uid))? {
+    if read_user_by_id(conn, r.userid)?.admin {
+      continue;
+    }
+    conn.execute(
+      "update orgauth_user set active = 0 where id = ?1",
+      params![r.userid.to_i64()],
+    )?;
+    remove_user_tokens(conn, r.userid)?;
+    conn.execute(
+      "insert into orgauth_invite_quota (user, quota, period_ms, revoked) values (?1, ?2, ?3, 1)
+        on conflict(user) do update set revoked = 1",
+      params![
+        r.userid.to_i64(),
+        config.invite_quota,
+        config.invite_quota_period_ms
+      ],
+    )?;
+    conn.execute(
+      "delete from orgauth_user_invite where creator = ?1",
+      params![r.userid.to_i64()],
+    )?;
+    disabled.push(r.userid);
+  }
+  Ok(disabled)
+}
+
 // change password, checking old password first.
 pub fn change_password(
   conn: &Connection,
@@ -853,6 +2168,7 @@ pub fn change_password(
         );
         userdata.hashwd = newhash;
         update_user(&conn, &userdata)?;
+        set_must_change_password(conn, uid, false)?;
         info!("changed password for {}", userdata.name.to_lowercase());
 
         Ok(())
@@ -966,7 +2282,8 @@ pub fn change_email(
         // bad password, can't change.
         bail!("invalid password!")
       } else {
-        // create a 'newemail' record.
+        // create a 'newemail' record, replacing any earlier requests.
+        cancel_newemails(conn, uid, None)?;
         let token = Uuid::new_v4();
         add_newemail(&conn, uid, token, cp.email)?;
 
@@ -976,6 +2293,62 @@ pub fn change_email(
   }
 }
 
+// log the user out everywhere.
+pub fn remove_user_tokens(conn: &Connection, uid: UserId) -> Result<(), error::Error> {
+  conn.execute(
+    "delete from orgauth_token where user = ?1",
+    params!(uid.to_i64()),
+  )?;
+  Ok(())
+}
+
+// orgs owned by uid go to their longest standing admin, or failing that member.  Orgs
+// with no one else in them are deleted, along with their invites.
+fn hand_over_orgs(conn: &Connection, uid: UserId) -> Result<(), error::Error> {
+  let mut pstmt =
+    conn.prepare("select org from orgauth_org_member where user = ?1 and role = ?2")?;
+  let orgs = pstmt
+    .query_map(
+      params![uid.to_i64(), org_role_str(OrgRole::OrOwner)],
+      |row| row.get::<usize, i64>(0),
+    )?
+    .collect::<Result<Vec<i64>, rusqlite::Error>>()?;
+
+  for org in orgs.into_iter().map(OrgId::Oid) {
+    match conn.query_row(
+      "select user from orgauth_org_member where org = ?1 and user != ?2
+        order by role = ?3 desc, createdate, user limit 1",
+      params![org.to_i64(), uid.to_i64(), org_role_str(OrgRole::OrAdmin)],
+      |row| row.get::<usize, i64>(0),
+    ) {
+      Ok(next) => {
+        info!("org {} passes from user {} to user {}", org, uid, next);
+        transfer_org(conn, org, UserId::Uid(next))?;
+      }
+      Err(rusqlite::Error::QueryReturnedNoRows) => {
+        info!(
+          "deleting org {}, which has no members besides user {}",
+          org, uid
+        );
+        conn.execute(
+          "delete from orgauth_user_invite where org = ?1",
+          params![org.to_i64()],
+        )?;
+        conn.execute(
+          "delete from orgauth_org_member where org = ?1",
+          params![org.to_i64()],
+        )?;
+        conn.execute(
+          "delete from orgauth_org where id = ?1",
+          params![org.to_i64()],
+        )?;
+      }
+      Err(e) => return Err(e.into()),
+    }
+  }
+  Ok(())
+}
+
 pub fn delete_user(conn: &Connection, uid: UserId) -> Result<(), error::Error> {
   info!("deleting user: {}", uid);
   conn.execute(
@@ -986,14 +2359,1354 @@ pub fn delete_user(conn: &Connection, uid: UserId) -> Result<(), error::Error> {
     "delete from orgauth_newemail where user = ?1",
     params!(uid.to_i64()),
   )?;
+  conn.execute(
+    "delete from orgauth_email_revert where user = ?1",
+    params!(uid.to_i64()),
+  )?;
+  conn.execute(
+    "delete from orgauth_email_verify where user = ?1",
+    params!(uid.to_i64()),
+  )?;
   conn.execute(
     "delete from orgauth_newpassword where user = ?1",
     params!(uid.to_i64()),
   )?;
   conn.execute(
-    "delete from orgauth_user where id = ?1",
+    "delete from orgauth_user_role where user = ?1",
     params!(uid.to_i64()),
   )?;
-
-  Ok(())
+  hand_over_orgs(conn, uid)?;
+  conn.execute(
+    "delete from orgauth_org_member where user = ?1",
+    params!(uid.to_i64()),
+  )?;
+  conn.execute(
+    "delete from orgauth_group_member where user = ?1",
+    params!(uid.to_i64()),
+  )?;
+  conn.execute(
+    "update orgauth_group set owner = null where owner = ?1",
+    params!(uid.to_i64()),
+  )?;
+  conn.execute(
+    "delete from orgauth_user_profile where user = ?1",
+    params!(uid.to_i64()),
+  )?;
+  conn.execute(
+    "delete from orgauth_user_profile_field where user = ?1",
+    params!(uid.to_i64()),
+  )?;
+  conn.execute(
+    "delete from orgauth_username_history where user = ?1",
+    params!(uid.to_i64()),
+  )?;
+  // unused invites die with their creator; use reassign_userinvites to keep them.
+  conn.execute(
+    "delete from orgauth_user_invite where creator = ?1",
+    params!(uid.to_i64()),
+  )?;
+  conn.execute(
+    "update orgauth_org set creator = null where creator = ?1",
+    params!(uid.to_i64()),
+  )?;
+  conn.execute(
+    "delete from orgauth_user_suspension where user = ?1",
+    params!(uid.to_i64()),
+  )?;
+  conn.execute(
+    "update orgauth_user set creator = null where creator = ?1",
+    params!(uid.to_i64()),
+  )?;
+  conn.execute(
+    "delete from orgauth_token where impersonator = ?1",
+    params!(uid.to_i64()),
+  )?;
+  conn.execute(
+    "delete from orgauth_invite_use where user = ?1",
+    params!(uid.to_i64()),
+  )?;
+  conn.execute(
+    "delete from orgauth_invite_quota where user = ?1",
+    params!(uid.to_i64()),
+  )?;
+  conn.execute(
+    "delete from orgauth_invite_issue where creator = ?1",
+    params!(uid.to_i64()),
+  )?;
+  conn.execute(
+    "update orgauth_invite_use set creator = null where creator = ?1",
+    params!(uid.to_i64()),
+  )?;
+  // keep the audit trail.
+  conn.execute(
+    "update orgauth_audit_log set actor = null where actor = ?1",
+    params!(uid.to_i64()),
+  )?;
+  conn.execute(
+    "update orgauth_audit_log set target = null where target = ?1",
+    params!(uid.to_i64()),
+  )?;
+  conn.execute(
+    "update orgauth_user_suspension set suspended_by = null where suspended_by = ?1",
+    params!(uid.to_i64()),
+  )?;
+  conn.execute(
+    "delete from orgauth_user where id = ?1",
+    params!(uid.to_i64()),
+  )?;
+
+  Ok(())
+}
+
+// mark the user deleted, and log them out everywhere.  They can be restored
+// until purge_deleted_users removes them for good.
+pub fn soft_delete_user(conn: &Connection, uid: UserId) -> Result<(), error::Error> {
+  info!("soft deleting user: {}", uid);
+  let now = now()?;
+  conn.execute(
+    "update orgauth_user set deleted_date = ?1 where id = ?2 and deleted_date is null",
+    params![now, uid.to_i64()],
+  )?;
+  conn.execute(
+    "delete from orgauth_token where user = ?1",
+    params!(uid.to_i64()),
+  )?;
+  Ok(())
+}
+
+pub fn restore_user(conn: &Connection, uid: UserId) -> Result<(), error::Error> {
+  info!("restoring user: {}", uid);
+  let count = conn.execute(
+    "update orgauth_user set deleted_date = null where id = ?1 and deleted_date is not null",
+    params![uid.to_i64()],
+  )?;
+  if count == 0 {
+    return Err(format!("user {} is not deleted", uid).into());
+  }
+  Ok(())
+}
+
+pub fn user_deleted(conn: &Connection, uid: UserId) -> Result<bool, error::Error> {
+  let deleted_date: Option<i64> = conn.query_row(
+    "select deleted_date from orgauth_user where id = ?1",
+    params![uid.to_i64()],
+    |row| row.get(0),
+  )?;
+  Ok(deleted_date.is_some())
+}
+
+pub fn read_deleted_users(conn: &Connection) -> Result<Vec<DeletedUser>, error::Error> {
+  let mut pstmt = conn.prepare(
+    "select id, name, email, deleted_date from orgauth_user
+      where deleted_date is not null
+      order by deleted_date",
+  )?;
+  let r = pstmt
+    .query_map(params![], |row| {
+      Ok(DeletedUser {
+        userid: UserId::Uid(row.get(0)?),
+        name: row.get(1)?,
+        email: row.get(2)?,
+        deleted_date: row.get(3)?,
+      })
+    })?
+    .collect::<Result<Vec<DeletedUser>, rusqlite::Error>>()?;
+  Ok(r)
+}
+
+fn suspension_from_row(row: &rusqlite::Row) -> Result<Suspension, rusqlite::Error> {
+  Ok(Suspension {
+    userid: UserId::Uid(row.get(0)?),
+    reason: row.get(1)?,
+    until: row.get(2)?,
+    suspended_by: row.get::<usize, Option<i64>>(3)?.map(UserId::Uid),
+    createdate: row.get(4)?,
+  })
+}
+
+// suspending logs the user out everywhere; login is refused until the suspension
+// is lifted or runs out.
+pub fn suspend_user(
+  conn: &Connection,
+  admin: UserId,
+  su: &SuspendUser,
+) -> Result<Suspension, error::Error> {
+  info!("suspending user {}: {}", su.userid, su.reason);
+  let now = now()?;
+  conn.execute(
+    "insert into orgauth_user_suspension (user, reason, until, suspended_by, createdate)
+      values (?1, ?2, ?3, ?4, ?5)
+      on conflict(user) do update set reason = ?2, until = ?3, suspended_by = ?4, createdate = ?5",
+    params![su.userid.to_i64(), su.reason, su.until, admin.to_i64(), now],
+  )?;
+  conn.execute(
+    "delete from orgauth_token where user = ?1",
+    params!(su.userid.to_i64()),
+  )?;
+  Ok(Suspension {
+    userid: su.userid,
+    reason: su.reason.clone(),
+    until: su.until,
+    suspended_by: Some(admin),
+    createdate: now,
+  })
+}
+
+pub fn lift_suspension(conn: &Connection, uid: UserId) -> Result<(), error::Error> {
+  info!("lifting suspension for user {}", uid);
+  conn.execute(
+    "delete from orgauth_user_suspension where user = ?1",
+    params![uid.to_i64()],
+  )?;
+  Ok(())
+}
+
+// the user's current suspension.  Expired suspensions are removed here.
+pub fn read_suspension(conn: &Connection, uid: UserId) -> Result<Option<Suspension>, error::Error> {
+  let now = now()?;
+  conn.execute(
+    "delete from orgauth_user_suspension where user = ?1 and until < ?2",
+    params![uid.to_i64(), now],
+  )?;
+  match conn.query_row(
+    "select user, reason, until, suspended_by, createdate
+      from orgauth_user_suspension where user = ?1",
+    params![uid.to_i64()],
+    suspension_from_row,
+  ) {
+    Ok(s) => Ok(Some(s)),
+    Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+    Err(e) => Err(e.into()),
+  }
+}
+
+pub fn read_suspensions(conn: &Connection) -> Result<Vec<Suspension>, error::Error> {
+  let now = now()?;
+  conn.execute(
+    "delete from orgauth_user_suspension where until < ?1",
+    params![now],
+  )?;
+  let mut pstmt = conn.prepare(
+    "select user, reason, until, suspended_by, createdate
+      from orgauth_user_suspension order by createdate",
+  )?;
+  let r = pstmt
+    .query_map(params![], suspension_from_row)?
+    .collect::<Result<Vec<Suspension>, rusqlite::Error>>()?;
+  Ok(r)
+}
+
+pub fn reassign_userinvites(
+  conn: &Connection,
+  from: UserId,
+  to: UserId,
+) -> Result<(), error::Error> {
+  conn.execute(
+    "update orgauth_user_invite set creator = ?1 where creator = ?2",
+    params![to.to_i64(), from.to_i64()],
+  )?;
+  Ok(())
+}
+
+// hard delete users that were soft deleted more than grace_period_ms ago.
+// on_delete_user can veto a purge, in which case the user stays soft deleted.
+pub fn purge_deleted_users(
+  conn: &Connection,
+  grace_period_ms: i64,
+  on_delete_user: &mut dyn FnMut(&Connection, UserId) -> Result<bool, error::Error>,
+) -> Result<(), error::Error> {
+  let expdt = now()? - grace_period_ms;
+
+  let mut pstmt = conn.prepare(
+    "select id from orgauth_user
+      where deleted_date is not null and deleted_date < ?1",
+  )?;
+  let uids = pstmt
+    .query_map(params![expdt], |row| Ok(UserId::Uid(row.get(0)?)))?
+    .collect::<Result<Vec<UserId>, rusqlite::Error>>()?;
+
+  for uid in uids {
+    info!("purging deleted user {}", uid);
+    conn.execute("begin transaction", params!())?;
+    match (on_delete_user)(conn, uid).and_then(|ok| {
+      if ok {
+        delete_user(conn, uid)?;
+      }
+      Ok(ok)
+    }) {
+      Ok(true) => {
+        conn.execute("commit", params!())?;
+      }
+      Ok(false) => {
+        warn!("on_delete_user declined to purge user {}", uid);
+        conn.execute("rollback", params!())?;
+      }
+      Err(e) => {
+        error!("error purging user {}: {:?}", uid, e);
+        conn.execute("rollback", params!())?;
+      }
+    }
+  }
+
+  Ok(())
+}
+
+// everything but the host app's data, which comes from the export_user_data callback.
+pub fn export_user_data(conn: &Connection, uid: UserId) -> Result<UserDataExport, error::Error> {
+  let user = conn.query_row_and_then(
+    "select id, uuid, name, email, registration_key, admin, active, remote_url, createdate,
+        creator, last_login, last_seen, login_count, deleted_date
+      from orgauth_user where id = ?1",
+    params![uid.to_i64()],
+    |row| {
+      Ok::<_, error::Error>(ExportUser {
+        id: UserId::Uid(row.get(0)?),
+        uuid: Uuid::parse_str(row.get::<usize, String>(1)?.as_str())?,
+        name: row.get(2)?,
+        email: row.get(3)?,
+        registered: row.get::<usize, Option<String>>(4)?.is_none(),
+        admin: row.get(5)?,
+        active: row.get(6)?,
+        remote_url: row.get(7)?,
+        createdate: row.get(8)?,
+        creator: row.get::<usize, Option<i64>>(9)?.map(UserId::Uid),
+        last_login: row.get(10)?,
+        last_seen: row.get(11)?,
+        login_count: row.get(12)?,
+        deleted_date: row.get(13)?,
+      })
+    },
+  )?;
+
+  let mut pstmt = conn
+    .prepare("select tokendate, regendate from orgauth_token where user = ?1 order by tokendate")?;
+  let sessions = pstmt
+    .query_map(params![uid.to_i64()], |row| {
+      Ok(ExportSession {
+        tokendate: row.get(0)?,
+        regendate: row.get(1)?,
+      })
+    })?
+    .collect::<Result<Vec<ExportSession>, rusqlite::Error>>()?;
+
+  let mut pstmt = conn.prepare(
+    "select email, tokendate, org, data from orgauth_user_invite
+      where creator = ?1 order by tokendate",
+  )?;
+  let invites = pstmt
+    .query_map(params![uid.to_i64()], |row| {
+      Ok(ExportInvite {
+        email: row.get(0)?,
+        tokendate: row.get(1)?,
+        org: row.get::<usize, Option<i64>>(2)?.map(OrgId::Oid),
+        data: row.get(3)?,
+      })
+    })?
+    .collect::<Result<Vec<ExportInvite>, rusqlite::Error>>()?;
+
+  let mut pstmt = conn
+    .prepare("select email, tokendate from orgauth_newemail where user = ?1 order by tokendate")?;
+  let email_changes = pstmt
+    .query_map(params![uid.to_i64()], |row| {
+      Ok(ExportEmailChange {
+        email: row.get(0)?,
+        tokendate: row.get(1)?,
+      })
+    })?
+    .collect::<Result<Vec<ExportEmailChange>, rusqlite::Error>>()?;
+
+  let mut pstmt = conn.prepare(
+    "select name, changedate from orgauth_username_history where user = ?1 order by changedate",
+  )?;
+  let name_history = pstmt
+    .query_map(params![uid.to_i64()], |row| {
+      Ok(ExportNameChange {
+        name: row.get(0)?,
+        changedate: row.get(1)?,
+      })
+    })?
+    .collect::<Result<Vec<ExportNameChange>, rusqlite::Error>>()?;
+
+  Ok(UserDataExport {
+    user,
+    sessions,
+    invites,
+    email_changes,
+    name_history,
+    roles: read_user_roles(conn, uid)?,
+    orgs: read_user_orgs(conn, uid)?,
+    groups: read_user_groups(conn, uid)?,
+    profile: read_profile(conn, uid)?,
+    suspension: read_suspension(conn, uid)?,
+    audit_events: read_audit_log(
+      conn,
+      &AuditQuery {
+        user: Some(uid),
+        before: None,
+        limit: -1,
+      },
+    )?,
+    app_data: None,
+  })
+}
+
+fn role_id(conn: &Connection, name: &str) -> Result<i64, error::Error> {
+  match conn.query_row(
+    "select id from orgauth_role where name = ?1",
+    params![name],
+    |row| row.get(0),
+  ) {
+    Ok(id) => Ok(id),
+    Err(rusqlite::Error::QueryReturnedNoRows) => Err(format!("role not found: {}", name).into()),
+    Err(e) => Err(e.into()),
+  }
+}
+
+pub fn read_role_permissions(conn: &Connection, role: i64) -> Result<Vec<String>, error::Error> {
+  let mut pstmt = conn.prepare(
+    "select name from orgauth_permission
+      where role = ?1 order by name",
+  )?;
+  let r = pstmt
+    .query_map(params![role], |row| row.get(0))?
+    .collect::<Result<Vec<String>, rusqlite::Error>>()?;
+  Ok(r)
+}
+
+pub fn read_roles(conn: &Connection) -> Result<Vec<Role>, error::Error> {
+  let mut pstmt = conn.prepare("select id, name, description from orgauth_role order by name")?;
+  let rows = pstmt
+    .query_map(params![], |row| {
+      Ok((
+        row.get::<usize, i64>(0)?,
+        row.get::<usize, String>(1)?,
+        row.get::<usize, Option<String>>(2)?,
+      ))
+    })?
+    .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+
+  let mut roles = Vec::new();
+  for (id, name, description) in rows {
+    roles.push(Role {
+      name,
+      description,
+      permissions: read_role_permissions(conn, id)?,
+    });
+  }
+  Ok(roles)
+}
+
+pub fn read_role(conn: &Connection, name: &str) -> Result<Role, error::Error> {
+  let (id, description) = conn.query_row(
+    "select id, description from orgauth_role where name = ?1",
+    params![name],
+    |row| Ok((row.get::<usize, i64>(0)?, row.get(1)?)),
+  )?;
+  Ok(Role {
+    name: name.to_string(),
+    description,
+    permissions: read_role_permissions(conn, id)?,
+  })
+}
+
+// create the role, or update its description and replace its permissions.
+pub fn save_role(conn: &Connection, role: &Role) -> Result<(), error::Error> {
+  if role.name.trim() == "" {
+    bail!("role name can't be blank");
+  }
+  // the admin role means every permission; it isn't edited.
+  if role.name.trim() == ADMIN_ROLE {
+    bail!("can't change the built-in admin role");
+  }
+  let now = now()?;
+  conn.execute(
+    "insert into orgauth_role (name, description, createdate)
+      values (?1, ?2, ?3)
+      on conflict(name) do update set description = excluded.description",
+    params![role.name, role.description, now],
+  )?;
+  let id = role_id(conn, role.name.as_str())?;
+
+  conn.execute(
+    "delete from orgauth_permission where role = ?1",
+    params![id],
+  )?;
+  for p in role.permissions.iter() {
+    conn.execute(
+      "insert or ignore into orgauth_permission (role, name) values (?1, ?2)",
+      params![id, p],
+    )?;
+  }
+
+  Ok(())
+}
+
+pub fn delete_role(conn: &Connection, name: &str) -> Result<(), error::Error> {
+  if name == ADMIN_ROLE {
+    bail!("can't delete the built-in admin role");
+  }
+  let id = role_id(conn, name)?;
+  conn.execute("delete from orgauth_user_role where role = ?1", params![id])?;
+  conn.execute(
+    "delete from orgauth_permission where role = ?1",
+    params![id],
+  )?;
+  conn.execute("delete from orgauth_role where id = ?1", params![id])?;
+
+  Ok(())
+}
+
+pub fn read_user_roles(conn: &Connection, uid: UserId) -> Result<Vec<String>, error::Error> {
+  let mut pstmt = conn.prepare(
+    "select orgauth_role.name from orgauth_role, orgauth_user_role
+      where orgauth_role.id = orgauth_user_role.role
+        and orgauth_user_role.user = ?1
+      order by orgauth_role.name",
+  )?;
+  let r = pstmt
+    .query_map(params![uid.to_i64()], |row| row.get(0))?
+    .collect::<Result<Vec<String>, rusqlite::Error>>()?;
+  Ok(r)
+}
+
+// add or remove the admin role membership, without touching orgauth_user.admin.
+fn set_admin_role(conn: &Connection, uid: UserId, admin: bool) -> Result<(), error::Error> {
+  let id = role_id(conn, ADMIN_ROLE)?;
+  if admin {
+    conn.execute(
+      "insert or ignore into orgauth_user_role (user, role) values (?1, ?2)",
+      params![uid.to_i64(), id],
+    )?;
+  } else {
+    conn.execute(
+      "delete from orgauth_user_role where user = ?1 and role = ?2",
+      params![uid.to_i64(), id],
+    )?;
+  }
+  Ok(())
+}
+
+pub fn assign_role(conn: &Connection, ur: &UserRole) -> Result<(), error::Error> {
+  let id = role_id(conn, ur.role.as_str())?;
+  conn.execute(
+    "insert or ignore into orgauth_user_role (user, role) values (?1, ?2)",
+    params![ur.userid.to_i64(), id],
+  )?;
+  if ur.role == ADMIN_ROLE {
+    conn.execute(
+      "update orgauth_user set admin = 1 where id = ?1",
+      params![ur.userid.to_i64()],
+    )?;
+  }
+  Ok(())
+}
+
+pub fn revoke_role(conn: &Connection, ur: &UserRole) -> Result<(), error::Error> {
+  let id = role_id(conn, ur.role.as_str())?;
+  conn.execute(
+    "delete from orgauth_user_role where user = ?1 and role = ?2",
+    params![ur.userid.to_i64(), id],
+  )?;
+  if ur.role == ADMIN_ROLE {
+    conn.execute(
+      "update orgauth_user set admin = 0 where id = ?1",
+      params![ur.userid.to_i64()],
+    )?;
+  }
+  Ok(())
+}
+
+// does the user have this permission through any of their roles?
+// members of the admin role have every permission.
+pub fn has_permission(conn: &Connection, uid: UserId, perm: &str) -> Result<bool, error::Error> {
+  let count: i64 = conn.query_row(
+    "select count(*) from orgauth_user_role
+      join orgauth_role on orgauth_role.id = orgauth_user_role.role
+      left join orgauth_permission on orgauth_permission.role = orgauth_role.id
+      where orgauth_user_role.user = ?1
+        and (orgauth_role.name = ?2 or orgauth_permission.name = ?3)",
+    params![uid.to_i64(), ADMIN_ROLE, perm],
+    |row| row.get(0),
+  )?;
+  Ok(count > 0)
+}
+
+fn org_role_str(role: OrgRole) -> &'static str {
+  match role {
+    OrgRole::OrOwner => "owner",
+    OrgRole::OrAdmin => "admin",
+    OrgRole::OrMember => "member",
+  }
+}
+
+fn org_role_from_str(role: &str) -> Result<OrgRole, error::Error> {
+  match role {
+    "owner" => Ok(OrgRole::OrOwner),
+    "admin" => Ok(OrgRole::OrAdmin),
+    "member" => Ok(OrgRole::OrMember),
+    _ => Err(format!("invalid org role: {}", role).into()),
+  }
+}
+
+// create an org, with the creator as its owner.
+pub fn new_org(conn: &Connection, name: &str, owner: UserId) -> Result<OrgId, error::Error> {
+  if name.trim() == "" {
+    bail!("org name can't be blank");
+  }
+  let now = now()?;
+  conn.execute(
+    "insert into orgauth_org (name, creator, createdate) values (?1, ?2, ?3)",
+    params![name.trim(), owner.to_i64(), now],
+  )?;
+  let org = OrgId::Oid(conn.last_insert_rowid());
+
+  add_org_member(conn, org, owner, OrgRole::OrOwner)?;
+
+  Ok(org)
+}
+
+pub fn read_org(conn: &Connection, org: OrgId) -> Result<Org, error::Error> {
+  let name = conn.query_row(
+    "select name from orgauth_org where id = ?1",
+    params![org.to_i64()],
+    |row| row.get(0),
+  )?;
+  Ok(Org { id: org, name })
+}
+
+pub fn read_orgs(conn: &Connection) -> Result<Vec<Org>, error::Error> {
+  let mut pstmt = conn.prepare("select id, name from orgauth_org order by name")?;
+  let r = pstmt
+    .query_map(params![], |row| {
+      Ok(Org {
+        id: OrgId::Oid(row.get(0)?),
+        name: row.get(1)?,
+      })
+    })?
+    .collect::<Result<Vec<Org>, rusqlite::Error>>()?;
+  Ok(r)
+}
+
+pub fn read_user_orgs(conn: &Connection, uid: UserId) -> Result<Vec<OrgMembership>, error::Error> {
+  let mut pstmt = conn.prepare(
+    "select orgauth_org.id, orgauth_org.name, orgauth_org_member.role
+      from orgauth_org, orgauth_org_member
+      where orgauth_org.id = orgauth_org_member.org
+        and orgauth_org_member.user = ?1
+      order by orgauth_org.name",
+  )?;
+  let rows = pstmt
+    .query_map(params![uid.to_i64()], |row| {
+      Ok((
+        row.get::<usize, i64>(0)?,
+        row.get::<usize, String>(1)?,
+        row.get::<usize, String>(2)?,
+      ))
+    })?
+    .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+
+  let mut orgs = Vec::new();
+  for (id, name, role) in rows {
+    orgs.push(OrgMembership {
+      org: OrgId::Oid(id),
+      name,
+      role: org_role_from_str(role.as_str())?,
+    });
+  }
+  Ok(orgs)
+}
+
+// None if the user isn't a member.
+pub fn read_org_role(
+  conn: &Connection,
+  org: OrgId,
+  uid: UserId,
+) -> Result<Option<OrgRole>, error::Error> {
+  match conn.query_row(
+    "select role from orgauth_org_member where org = ?1 and user = ?2",
+    params![org.to_i64(), uid.to_i64()],
+    |row| row.get::<usize, String>(0),
+  ) {
+    Ok(role) => Ok(Some(org_role_from_str(role.as_str())?)),
+    Err(rusqlite::Error::QueryReturnedNoRows) => Ok(None),
+    Err(e) => Err(e.into()),
+  }
+}
+
+pub fn read_org_members(conn: &Connection, org: OrgId) -> Result<Vec<OrgMember>, error::Error> {
+  let mut pstmt = conn.prepare(
+    "select orgauth_user.id, orgauth_user.name, orgauth_org_member.role
+      from orgauth_user, orgauth_org_member
+      where orgauth_user.id = orgauth_org_member.user
+        and orgauth_org_member.org = ?1
+      order by orgauth_user.name",
+  )?;
+  let rows = pstmt
+    .query_map(params![org.to_i64()], |row| {
+      Ok((
+        row.get::<usize, i64>(0)?,
+        row.get::<usize, String>(1)?,
+        row.get::<usize, String>(2)?,
+      ))
+    })?
+    .collect::<Result<Vec<_>, rusqlite::Error>>()?;
+
+  let mut members = Vec::new();
+  for (id, name, role) in rows {
+    members.push(OrgMember {
+      userid: UserId::Uid(id),
+      name,
+      role: org_role_from_str(role.as_str())?,
+    });
+  }
+  Ok(members)
+}
+
+// adds the member, or leaves an existing membership unchanged.
+pub fn add_org_member(
+  conn: &Connection,
+  org: OrgId,
+  uid: UserId,
+  role: OrgRole,
+) -> Result<(), error::Error> {
+  let now = now()?;
+  conn.execute(
+    "insert or ignore into orgauth_org_member (org, user, role, createdate)
+      values (?1, ?2, ?3, ?4)",
+    params![org.to_i64(), uid.to_i64(), org_role_str(role), now],
+  )?;
+  Ok(())
+}
+
+pub fn set_org_member_role(
+  conn: &Connection,
+  org: OrgId,
+  uid: UserId,
+  role: OrgRole,
+) -> Result<(), error::Error> {
+  let count = conn.execute(
+    "update orgauth_org_member set role = ?1 where org = ?2 and user = ?3",
+    params![org_role_str(role), org.to_i64(), uid.to_i64()],
+  )?;
+  if count == 0 {
+    return Err(format!("user {} is not a member of org {}", uid, org).into());
+  }
+  Ok(())
+}
+
+pub fn remove_org_member(conn: &Connection, org: OrgId, uid: UserId) -> Result<(), error::Error> {
+  conn.execute(
+    "delete from orgauth_org_member where org = ?1 and user = ?2",
+    params![org.to_i64(), uid.to_i64()],
+  )?;
+  Ok(())
+}
+
+// make uid the owner; any previous owner becomes an org admin.
+pub fn transfer_org(conn: &Connection, org: OrgId, uid: UserId) -> Result<(), error::Error> {
+  if read_org_role(conn, org, uid)?.is_none() {
+    return Err(format!("user {} is not a member of org {}", uid, org).into());
+  }
+  conn.execute(
+    "update orgauth_org_member set role = ?1 where org = ?2 and role = ?3",
+    params![
+      org_role_str(OrgRole::OrAdmin),
+      org.to_i64(),
+      org_role_str(OrgRole::OrOwner)
+    ],
+  )?;
+  set_org_member_role(conn, org, uid, OrgRole::OrOwner)
+}
+
+fn group_from_row(row: &rusqlite::Row) -> Result<Group, rusqlite::Error> {
+  Ok(Group {
+    id: GroupId::Gid(row.get(0)?),
+    name: row.get(1)?,
+    owner: row.get::<usize, Option<i64>>(2)?.map(UserId::Uid),
+  })
+}
+
+pub fn new_group(conn: &Connection, name: &str, owner: UserId) -> Result<GroupId, error::Error> {
+  if name.trim() == "" {
+    bail!("group name can't be blank");
+  }
+  let now = now()?;
+  conn.execute(
+    "insert into orgauth_group (name, owner, createdate) values (?1, ?2, ?3)",
+    params![name.trim(), owner.to_i64(), now],
+  )?;
+  Ok(GroupId::Gid(conn.last_insert_rowid()))
+}
+
+pub fn read_group(conn: &Connection, group: GroupId) -> Result<Group, error::Error> {
+  let g = conn.query_row(
+    "select id, name, owner from orgauth_group where id = ?1",
+    params![group.to_i64()],
+    group_from_row,
+  )?;
+  Ok(g)
+}
+
+// all groups, or only those owned by owner.
+pub fn read_groups(conn: &Connection, owner: Option<UserId>) -> Result<Vec<Group>, error::Error> {
+  let mut pstmt = conn.prepare(
+    "select id, name, owner from orgauth_group
+      where ?1 is null or owner = ?1
+      order by name",
+  )?;
+  let r = pstmt
+    .query_map(params![owner.map(|o| *o.to_i64())], group_from_row)?
+    .collect::<Result<Vec<Group>, rusqlite::Error>>()?;
+  Ok(r)
+}
+
+pub fn read_group_info(conn: &Connection, group: GroupId) -> Result<GroupInfo, error::Error> {
+  let mut mstmt = conn.prepare(
+    "select orgauth_user.id, orgauth_user.name
+      from orgauth_user, orgauth_group_member
+      where orgauth_user.id = orgauth_group_member.user
+        and orgauth_group_member.grp = ?1
+      order by orgauth_user.name",
+  )?;
+  let members = mstmt
+    .query_map(params![group.to_i64()], |row| {
+      Ok(GroupMember {
+        userid: UserId::Uid(row.get(0)?),
+        name: row.get(1)?,
+      })
+    })?
+    .collect::<Result<Vec<GroupMember>, rusqlite::Error>>()?;
+
+  let mut sstmt = conn.prepare(
+    "select orgauth_group.id, orgauth_group.name, orgauth_group.owner
+      from orgauth_group, orgauth_group_nesting
+      where orgauth_group.id = orgauth_group_nesting.child
+        and orgauth_group_nesting.parent = ?1
+      order by orgauth_group.name",
+  )?;
+  let subgroups = sstmt
+    .query_map(params![group.to_i64()], group_from_row)?
+    .collect::<Result<Vec<Group>, rusqlite::Error>>()?;
+
+  Ok(GroupInfo {
+    group: read_group(conn, group)?,
+    members,
+    subgroups,
+  })
+}
+
+pub fn rename_group(conn: &Connection, group: GroupId, name: &str) -> Result<(), error::Error> {
+  if name.trim() == "" {
+    bail!("group name can't be blank");
+  }
+  conn.execute(
+    "update orgauth_group set name = ?1 where id = ?2",
+    params![name.trim(), group.to_i64()],
+  )?;
+  Ok(())
+}
+
+pub fn delete_group(conn: &Connection, group: GroupId) -> Result<(), error::Error> {
+  conn.execute(
+    "delete from orgauth_group_member where grp = ?1",
+    params![group.to_i64()],
+  )?;
+  conn.execute(
+    "delete from orgauth_group_nesting where parent = ?1 or child = ?1",
+    params![group.to_i64()],
+  )?;
+  conn.execute(
+    "delete from orgauth_group where id = ?1",
+    params![group.to_i64()],
+  )?;
+  Ok(())
+}
+
+pub fn add_group_member(
+  conn: &Connection,
+  group: GroupId,
+  uid: UserId,
+) -> Result<(), error::Error> {
+  conn.execute(
+    "insert or ignore into orgauth_group_member (grp, user) values (?1, ?2)",
+    params![group.to_i64(), uid.to_i64()],
+  )?;
+  Ok(())
+}
+
+pub fn remove_group_member(
+  conn: &Connection,
+  group: GroupId,
+  uid: UserId,
+) -> Result<(), error::Error> {
+  conn.execute(
+    "delete from orgauth_group_member where grp = ?1 and user = ?2",
+    params![group.to_i64(), uid.to_i64()],
+  )?;
+  Ok(())
+}
+
+// nest subgroup within group, refusing to make a cycle.
+pub fn add_subgroup(
+  conn: &Connection,
+  group: GroupId,
+  subgroup: GroupId,
+) -> Result<(), error::Error> {
+  let cycle: i64 = conn.query_row(
+    "with recursive descendants(grp) as (
+        select ?1
+        union
+        select orgauth_group_nesting.child from orgauth_group_nesting, descendants
+          where orgauth_group_nesting.parent = descendants.grp)
+      select count(*) from descendants where grp = ?2",
+    params![subgroup.to_i64(), group.to_i64()],
+    |row| row.get(0),
+  )?;
+  if cycle > 0 {
+    return Err(format!("group {} already contains group {}", subgroup, group).into());
+  }
+  conn.execute(
+    "insert or ignore into orgauth_group_nesting (parent, child) values (?1, ?2)",
+    params![group.to_i64(), subgroup.to_i64()],
+  )?;
+  Ok(())
+}
+
+pub fn remove_subgroup(
+  conn: &Connection,
+  group: GroupId,
+  subgroup: GroupId,
+) -> Result<(), error::Error> {
+  conn.execute(
+    "delete from orgauth_group_nesting where parent = ?1 and child = ?2",
+    params![group.to_i64(), subgroup.to_i64()],
+  )?;
+  Ok(())
+}
+
+// groups the user belongs to, directly or through nested groups.
+pub fn read_user_groups(conn: &Connection, uid: UserId) -> Result<Vec<Group>, error::Error> {
+  let mut pstmt = conn.prepare(
+    "with recursive usergroups(grp) as (
+        select grp from orgauth_group_member where user = ?1
+        union
+        select orgauth_group_nesting.parent from orgauth_group_nesting, usergroups
+          where orgauth_group_nesting.child = usergroups.grp)
+      select id, name, owner from orgauth_group
+        where id in (select grp from usergroups)
+        order by name",
+  )?;
+  let r = pstmt
+    .query_map(params![uid.to_i64()], group_from_row)?
+    .collect::<Result<Vec<Group>, rusqlite::Error>>()?;
+  Ok(r)
+}
+
+// users belonging to the group, directly or through nested groups.
+pub fn read_group_users(conn: &Connection, group: GroupId) -> Result<Vec<UserId>, error::Error> {
+  let mut pstmt = conn.prepare(
+    "with recursive groups(grp) as (
+        select ?1
+        union
+        select orgauth_group_nesting.child from orgauth_group_nesting, groups
+          where orgauth_group_nesting.parent = groups.grp)
+      select distinct user from orgauth_group_member
+        where grp in (select grp from groups)",
+  )?;
+  let r = pstmt
+    .query_map(params![group.to_i64()], |row| Ok(UserId::Uid(row.get(0)?)))?
+    .collect::<Result<Vec<UserId>, rusqlite::Error>>()?;
+  Ok(r)
+}
+
+// for host app authorization checks; includes membership through nested groups.
+pub fn is_group_member(
+  conn: &Connection,
+  uid: UserId,
+  group: GroupId,
+) -> Result<bool, error::Error> {
+  Ok(read_user_groups(conn, uid)?.iter().any(|g| g.id == group))
+}
+
+// users without a profile record get an empty profile.
+pub fn read_profile(conn: &Connection, uid: UserId) -> Result<UserProfile, error::Error> {
+  let mut profile = match conn.query_row(
+    "select display_name, avatar, locale, timezone, bio
+      from orgauth_user_profile where user = ?1",
+    params![uid.to_i64()],
+    |row| {
+      Ok(UserProfile {
+        userid: uid,
+        display_name: row.get(0)?,
+        avatar: row.get(1)?,
+        locale: row.get(2)?,
+        timezone: row.get(3)?,
+        bio: row.get(4)?,
+        custom: Vec::new(),
+      })
+    },
+  ) {
+    Ok(p) => p,
+    Err(rusqlite::Error::QueryReturnedNoRows) => UserProfile {
+      userid: uid,
+      display_name: None,
+      avatar: None,
+      locale: None,
+      timezone: None,
+      bio: None,
+      custom: Vec::new(),
+    },
+    Err(e) => return Err(e.into()),
+  };
+
+  let mut pstmt = conn.prepare(
+    "select name, value from orgauth_user_profile_field
+      where user = ?1 order by name",
+  )?;
+  profile.custom = pstmt
+    .query_map(params![uid.to_i64()], |row| {
+      Ok(ProfileFieldValue {
+        name: row.get(0)?,
+        value: row.get(1)?,
+      })
+    })?
+    .collect::<Result<Vec<ProfileFieldValue>, rusqlite::Error>>()?;
+
+  Ok(profile)
+}
+
+const MAX_DISPLAY_NAME: usize = 100;
+const MAX_AVATAR: usize = 64 * 1024;
+const MAX_LOCALE: usize = 35;
+const MAX_TIMEZONE: usize = 64;
+const MAX_BIO: usize = 4000;
+
+fn is_url(s: &str) -> bool {
+  s.starts_with("https://") || s.starts_with("http://")
+}
+
+// raster images only; svg can carry scripts.
+fn is_image_data_url(s: &str) -> bool {
+  match s.strip_prefix("data:image/") {
+    Some(rest) => ["png", "jpeg", "gif", "webp"].iter().any(|t| {
+      rest
+        .strip_prefix(t)
+        .is_some_and(|r| r.starts_with(';') || r.starts_with(','))
+    }),
+    None => false,
+  }
+}
+
+// returns an empty list if the profile is valid.
+pub fn validate_profile(
+  fields: &[ProfileFieldDef],
+  profile: &UserProfile,
+) -> Vec<ProfileFieldError> {
+  let mut errors = Vec::new();
+  let mut err = |field: &str, message: String| {
+    errors.push(ProfileFieldError {
+      field: field.to_string(),
+      message,
+    })
+  };
+
+  for (field, value, max) in [
+    ("display_name", &profile.display_name, MAX_DISPLAY_NAME),
+    ("avatar", &profile.avatar, MAX_AVATAR),
+    ("locale", &profile.locale, MAX_LOCALE),
+    ("timezone", &profile.timezone, MAX_TIMEZONE),
+    ("bio", &profile.bio, MAX_BIO),
+  ] {
+    if let Some(v) = value {
+      if v.chars().count() > max {
+        err(field, format!("longer than {} characters", max));
+      }
+    }
+  }
+
+  if let Some(avatar) = &profile.avatar {
+    if !(is_url(avatar) || is_image_data_url(avatar)) {
+      err(
+        "avatar",
+        "must be an http(s) url, or a png, jpeg, gif or webp data url".to_string(),
+      );
+    }
+  }
+  if let Some(locale) = &profile.locale {
+    if !locale
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+      err("locale", "not a valid locale tag".to_string());
+    }
+  }
+  if let Some(timezone) = &profile.timezone {
+    if !timezone
+      .chars()
+      .all(|c| c.is_ascii_alphanumeric() || "/_+-".contains(c))
+    {
+      err("timezone", "not a valid timezone name".to_string());
+    }
+  }
+
+  for v in profile.custom.iter() {
+    match fields.iter().find(|f| f.name == v.name) {
+      None => err(v.name.as_str(), "unknown profile field".to_string()),
+      Some(def) => {
+        if let Some(max) = def.max_length {
+          if v.value.chars().count() as i64 > max {
+            err(v.name.as_str(), format!("longer than {} characters", max));
+          }
+        }
+        let valid = match def.field_type {
+          ProfileFieldType::PftText => true,
+          ProfileFieldType::PftInteger => v.value.parse::<i64>().is_ok(),
+          ProfileFieldType::PftBool => v.value == "true" || v.value == "false",
+          ProfileFieldType::PftUrl => is_url(v.value.as_str()),
+        };
+        if !valid {
+          err(v.name.as_str(), format!("not a valid {:?}", def.field_type));
+        }
+      }
+    }
+  }
+
+  for def in fields.iter().filter(|f| f.required) {
+    if !profile
+      .custom
+      .iter()
+      .any(|v| v.name == def.name && v.value.trim() != "")
+    {
+      err(def.name.as_str(), "required".to_string());
+    }
+  }
+
+  errors
+}
+
+// replaces the user's profile.  validate first!
+pub fn save_profile(conn: &Connection, profile: &UserProfile) -> Result<(), error::Error> {
+  conn.execute(
+    "insert into orgauth_user_profile (user, display_name, avatar, locale, timezone, bio)
+      values (?1, ?2, ?3, ?4, ?5, ?6)
+      on conflict(user) do update set
+        display_name = excluded.display_name,
+        avatar = excluded.avatar,
+        locale = excluded.locale,
+        timezone = excluded.timezone,
+        bio = excluded.bio",
+    params![
+      profile.userid.to_i64(),
+      profile.display_name,
+      profile.avatar,
+      profile.locale,
+      profile.timezone,
+      profile.bio
+    ],
+  )?;
+
+  conn.execute(
+    "delete from orgauth_user_profile_field where user = ?1",
+    params![profile.userid.to_i64()],
+  )?;
+  for v in profile.custom.iter() {
+    conn.execute(
+      "insert or replace into orgauth_user_profile_field (user, name, value)
+        values (?1, ?2, ?3)",
+      params![profile.userid.to_i64(), v.name, v.value],
+    )?;
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::endpoints::tests::test_user;
+  use crate::migrations::tests::test_db;
+
+  #[test]
+  fn test_soft_delete_purge() {
+    let (conn, _) = test_db("test_soft_delete_purge");
+    let alice = test_user(&conn, "alice", false);
+    let bob = test_user(&conn, "bob", false);
+    let carol = test_user(&conn, "carol", false);
+    add_token(&conn, alice, Uuid::new_v4(), None).unwrap();
+    add_userinvite(
+      &conn,
+      Uuid::new_v4(),
+      None,
+      alice,
+      None,
+      None,
+      now().unwrap() + 100000,
+      1,
+    )
+    .unwrap();
+    let count = |sql: &str| -> i64 { conn.query_row(sql, params![], |row| row.get(0)).unwrap() };
+
+    // soft deletion logs the user out, and can be undone.
+    soft_delete_user(&conn, alice).unwrap();
+    assert!(user_deleted(&conn, alice).unwrap());
+    assert_eq!(count("select count(*) from orgauth_token"), 0);
+    assert_eq!(read_deleted_users(&conn).unwrap().len(), 1);
+    restore_user(&conn, alice).unwrap();
+    assert!(!user_deleted(&conn, alice).unwrap());
+    assert!(restore_user(&conn, alice).is_err());
+
+    // nothing is purged during the grace period.
+    for uid in [alice, bob, carol] {
+      soft_delete_user(&conn, uid).unwrap();
+    }
+    let deleted = std::cell::RefCell::new(Vec::new());
+    let mut on_delete_user = |_: &Connection, uid: UserId| -> Result<bool, error::Error> {
+      deleted.borrow_mut().push(uid);
+      // bob's app data won't go, and carol's fails.
+      if uid == bob {
+        Ok(false)
+      } else if uid == carol {
+        Err("app error".into())
+      } else {
+        Ok(true)
+      }
+    };
+    purge_deleted_users(&conn, 100000, &mut on_delete_user).unwrap();
+    assert!(deleted.borrow().is_empty());
+
+    // afterwards the callback runs, and only the users it agreed to are removed.
+    conn
+      .execute("update orgauth_user set deleted_date = 1", params![])
+      .unwrap();
+    purge_deleted_users(&conn, 100000, &mut on_delete_user).unwrap();
+    assert_eq!(*deleted.borrow(), vec![alice, bob, carol]);
+    assert!(read_user_by_id(&conn, alice).is_err());
+    assert_eq!(count("select count(*) from orgauth_user_invite"), 0);
+    assert!(user_deleted(&conn, bob).unwrap());
+    assert!(user_deleted(&conn, carol).unwrap());
+  }
+
+  #[test]
+  fn test_roles() {
+    let (conn, _) = test_db("test_roles");
+    let admin = test_user(&conn, "admin", true);
+    let user = test_user(&conn, "user", false);
+    let editor = Role {
+      name: "editor".to_string(),
+      description: None,
+      permissions: vec!["edit".to_string()],
+    };
+    save_role(&conn, &editor).unwrap();
+
+    // admins have every permission, through the built-in role.
+    assert_eq!(read_user_roles(&conn, admin).unwrap(), vec![ADMIN_ROLE]);
+    assert!(has_permission(&conn, admin, "edit").unwrap());
+    assert!(has_permission(&conn, admin, "anything").unwrap());
+    assert!(!has_permission(&conn, user, "edit").unwrap());
+
+    let ur = |role: &str| UserRole {
+      userid: user,
+      role: role.to_string(),
+    };
+    assign_role(&conn, &ur("editor")).unwrap();
+    assert!(has_permission(&conn, user, "edit").unwrap());
+    assert!(!has_permission(&conn, user, "bill").unwrap());
+    revoke_role(&conn, &ur("editor")).unwrap();
+    assert!(!has_permission(&conn, user, "edit").unwrap());
+    assert!(assign_role(&conn, &ur("nosuchrole")).is_err());
+
+    // the admin role and the admin flag go together.
+    assign_role(&conn, &ur(ADMIN_ROLE)).unwrap();
+    assert!(read_user_by_id(&conn, user).unwrap().admin);
+    revoke_role(&conn, &ur(ADMIN_ROLE)).unwrap();
+    assert!(!read_user_by_id(&conn, user).unwrap().admin);
+
+    // deleting a role takes it away from its users.
+    assign_role(&conn, &ur("editor")).unwrap();
+    delete_role(&conn, "editor").unwrap();
+    assert!(read_user_roles(&conn, user).unwrap().is_empty());
+    assert!(!has_permission(&conn, user, "edit").unwrap());
+
+    // the admin role can't be changed or deleted.
+    let admin_role = Role {
+      name: ADMIN_ROLE.to_string(),
+      description: None,
+      permissions: vec![],
+    };
+    assert!(save_role(&conn, &admin_role).is_err());
+    assert!(delete_role(&conn, ADMIN_ROLE).is_err());
+    assert!(has_permission(&conn, admin, "edit").unwrap());
+  }
+
+  fn profile(custom: Vec<(&str, &str)>) -> UserProfile {
+    UserProfile {
+      userid: UserId::Uid(1),
+      display_name: Some("Some Body".to_string()),
+      avatar: Some("https://example.com/a.png".to_string()),
+      locale: Some("en-US".to_string()),
+      timezone: Some("America/New_York".to_string()),
+      bio: None,
+      custom: custom
+        .iter()
+        .map(|(n, v)| ProfileFieldValue {
+          name: n.to_string(),
+          value: v.to_string(),
+        })
+        .collect(),
+    }
+  }
+
+  #[test]
+  fn test_validate_profile() {
+    let fields = vec![
+      ProfileFieldDef {
+        name: "age".to_string(),
+        field_type: ProfileFieldType::PftInteger,
+        required: true,
+        max_length: Some(3),
+      },
+      ProfileFieldDef {
+        name: "site".to_string(),
+        field_type: ProfileFieldType::PftUrl,
+        required: false,
+        max_length: None,
+      },
+    ];
+
+    assert!(validate_profile(&fields, &profile(vec![("age", "42")])).is_empty());
+    // missing required field.
+    assert_eq!(validate_profile(&fields, &profile(vec![])).len(), 1);
+    // bad integer, bad url, unknown field.
+    assert_eq!(
+      validate_profile(
+        &fields,
+        &profile(vec![("age", "x"), ("site", "ftp://a"), ("shoe", "9")])
+      )
+      .len(),
+      3
+    );
+
+    let mut p = profile(vec![("age", "1")]);
+    p.avatar = Some("javascript:alert(1)".to_string());
+    p.timezone = Some("<b>".to_string());
+    assert_eq!(validate_profile(&fields, &p).len(), 2);
+
+    for (avatar, valid) in [
+      ("https://example.com/me.png", true),
+      ("data:image/png;base64,iVBORw0KGgo=", true),
+      ("data:image/webp;base64,UklGRg==", true),
+      ("data:image/svg+xml;base64,PHN2Zz4=", false),
+      ("data:image/pngx;base64,AA==", false),
+      ("data:text/html,<script>", false),
+    ] {
+      let mut p = profile(vec![("age", "1")]);
+      p.avatar = Some(avatar.to_string());
+      assert_eq!(
+        validate_profile(&fields, &p).is_empty(),
+        valid,
+        "{}",
+        avatar
+      );
+    }
+  }
 }
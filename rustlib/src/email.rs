@@ -1,3 +1,4 @@
+use crate::data::User;
 use crate::error;
 use crate::util;
 use lettre::{transport::smtp::response::Response, Message, SmtpTransport, Transport};
@@ -39,6 +40,74 @@ pub fn send_newemail_confirmation(
   mailer.send(&email).map_err(|e| e.into())
 }
 
+pub fn send_email_changed_notice(
+  appname: &str,
+  domain: &str,
+  mainsite: &str,
+  user: &User,
+  new_email: &str,
+  revert_token: &str,
+  revert_until: i64,
+) -> Result<Response, error::Error> {
+  info!(
+    "Sending email change notice to old address for user: {}",
+    user.name
+  );
+  let text = format!(
+    "The email address for {} user '{}' was changed to {}.\n\
+     If you didn't do this, click the link to change it back and log out all sessions,\n\
+     then reset your password.  The link works until {}.\n\
+     {}/revertemail/{}/{}",
+    appname,
+    user.name,
+    new_email,
+    util::show_time(revert_until / 1000).unwrap_or(revert_until.to_string()),
+    mainsite,
+    user.name,
+    revert_token
+  );
+  let email = Message::builder()
+    .from(format!("no-reply@{}", domain).parse()?)
+    .to(user.email.parse()?)
+    .subject(format!("{} email changed", appname).to_string())
+    .body(text.clone())?;
+
+  // to help with testing for desktop use, or if the server is barred from sending email.
+  util::write_string("last-email-revert.txt", text.as_str())?;
+
+  let mailer = SmtpTransport::unencrypted_localhost();
+  // Send the email
+  mailer.send(&email).map_err(|e| e.into())
+}
+
+pub fn send_email_verification(
+  appname: &str,
+  domain: &str,
+  mainsite: &str,
+  email: &str,
+  uid: &str,
+  token: &str,
+) -> Result<Response, error::Error> {
+  info!("Sending email verification for user: {}", uid);
+  let text = format!(
+    "Click the link to verify your email, {} user '{}'!\n\
+     {}/verifyemail/{}/{}",
+    appname, uid, mainsite, uid, token
+  );
+  let email = Message::builder()
+    .from(format!("no-reply@{}", domain).parse()?)
+    .to(email.parse()?)
+    .subject(format!("verify {} email", appname).to_string())
+    .body(text.clone())?;
+
+  // to help with verification for desktop use, or if the server is barred from sending email.
+  util::write_string("last-email-verify.txt", text.as_str())?;
+
+  let mailer = SmtpTransport::unencrypted_localhost();
+  // Send the email
+  mailer.send(&email).map_err(|e| e.into())
+}
+
 pub fn send_registration(
   appname: &str,
   domain: &str,
@@ -115,6 +184,76 @@ pub fn send_reset(
   mailer.send(&email).map_err(|e| e.into())
 }
 
+pub fn send_invite(
+  appname: &str,
+  domain: &str,
+  email: &str,
+  inviter: &str,
+  url: &str,
+  message: Option<&str>,
+) -> Result<Response, error::Error> {
+  info!("sending invite email from user: {}", inviter);
+  let text = format!(
+    "{} has invited you to join {}!\n\
+     {}\
+     Click the link to sign up:\n\
+     {}",
+    inviter,
+    appname,
+    message
+      .map(|m| format!("\n{}\n\n", m))
+      .unwrap_or("".to_string()),
+    url
+  );
+  let email = Message::builder()
+    .from(format!("no-reply@{}", domain).parse()?)
+    .to(email.parse()?)
+    .subject(format!("{} invite", appname).to_string())
+    .body(text.clone())?;
+
+  // to help with invites for desktop use, or if the server is barred from sending email.
+  util::write_string("last-email-invite.txt", text.as_str())?;
+
+  let mailer = SmtpTransport::unencrypted_localhost();
+  // Send the email
+  mailer.send(&email).map_err(|e| e.into())
+}
+
+pub fn send_registration_decision(
+  appname: &str,
+  domain: &str,
+  mainsite: &str,
+  email: &str,
+  uid: &str,
+  approved: bool,
+  message: Option<&str>,
+) -> Result<Response, error::Error> {
+  info!("sending registration decision for user: {}", uid);
+  let decision = if approved {
+    format!(
+      "Your {} registration for user '{}' has been approved!\n{}",
+      appname, uid, mainsite
+    )
+  } else {
+    format!(
+      "Your {} registration for user '{}' was not approved.",
+      appname, uid
+    )
+  };
+  let email = Message::builder()
+    .from(format!("no-reply@{}", domain).parse()?)
+    .to(email.parse()?)
+    .subject(format!("{} registration", appname).to_string())
+    .body(match message {
+      Some(m) => format!("{}\n\n{}", decision, m),
+      None => decision,
+    })?;
+
+  let mailer = SmtpTransport::unencrypted_localhost();
+  // Send the email
+  mailer.send(&email).map_err(|e| e.into())
+}
+
 pub fn send_registration_notification(
   appname: &str,
   domain: &str,
@@ -159,3 +298,32 @@ pub fn send_rsvp_notification(
   // Send the email
   mailer.send(&email).map_err(|e| e.into())
 }
+
+pub fn send_account_deleted(
+  appname: &str,
+  domain: &str,
+  email: &str,
+  uid: &str,
+  purge_date: Option<i64>,
+) -> Result<Response, error::Error> {
+  info!("sending account deletion confirmation for user: {}", uid);
+  let body = match purge_date {
+    Some(pd) => format!(
+      "Your {} account '{}' has been deleted, and will be permanently removed on {}.\n\
+       Contact the site admin before then if this was a mistake.",
+      appname,
+      uid,
+      util::show_time(pd / 1000).unwrap_or(pd.to_string())
+    ),
+    None => format!("Your {} account '{}' has been deleted.", appname, uid),
+  };
+  let email = Message::builder()
+    .from(format!("no-reply@{}", domain).parse()?)
+    .to(email.parse()?)
+    .subject(format!("{} account deleted", appname).to_string())
+    .body(body)?;
+
+  let mailer = SmtpTransport::unencrypted_localhost();
+  // Send the email
+  mailer.send(&email).map_err(|e| e.into())
+}
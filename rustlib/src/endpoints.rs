@@ -1,10 +1,16 @@
 use crate::data::{
-  AdminRequest, AdminResponse, AuthedRequest, Config, Login, PhantomUser, PwdReset,
-  RegistrationData, User, UserId, UserInvite, UserRequest, UserResponse,
+  admin_settings, AdminRequest, AdminResponse, AuthedRequest, BulkOp, BulkOutcome, BulkResult,
+  ChangePassword, Config, CreateUser, CreatedUser, EmailVerification, GetInvite, GroupId,
+  GroupRequest, GroupResponse, ImportMode, ImportOutcome, ImportRow, ImportSource, ImportUser,
+  ImportUsers, Login, NewUserPassword, OrgId, OrgMembers, OrgRequest, OrgRole, PhantomUser,
+  PwdReset, RegistrationData, SuspensionNotice, User, UserGroups, UserId, UserInvite, UserRequest,
+  UserResponse, UsernameError, DEFAULT_EMAIL_REVERT_EXPIRATION_MS,
+  DEFAULT_IMPERSONATION_EXPIRATION_MS, DEFAULT_REGISTRATION_RESEND_INTERVAL_MS,
 };
 use crate::dbfun;
 use crate::email;
 use crate::error;
+use crate::username;
 use crate::util;
 use crate::util::is_token_expired;
 use actix_session::Session;
@@ -14,7 +20,11 @@ use reqwest;
 use rusqlite::{params, Connection};
 use serde_json;
 use sha256;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::{Mutex, OnceLock};
+use std::time::SystemTime;
 use util::now;
 use uuid::Uuid;
 
@@ -32,6 +42,21 @@ pub struct Callbacks {
   pub extra_login_data:
     Box<dyn FnMut(&Connection, UserId) -> Result<Option<serde_json::Value>, error::Error>>,
   pub on_delete_user: Box<dyn FnMut(&Connection, UserId) -> Result<bool, error::Error>>,
+  // the app's own data for this user, for data exports.
+  pub export_user_data: Box<ExportUserData>,
+}
+
+pub type ExportUserData =
+  dyn FnMut(&Connection, UserId) -> Result<Option<serde_json::Value>, error::Error>;
+
+fn export_user_data(
+  conn: &Connection,
+  callbacks: &mut Callbacks,
+  uid: UserId,
+) -> Result<String, error::Error> {
+  let mut export = dbfun::export_user_data(conn, uid)?;
+  export.app_data = (callbacks.export_user_data)(conn, uid)?;
+  Ok(serde_json::to_string(&export)?)
 }
 
 pub trait Tokener {
@@ -80,6 +105,13 @@ pub fn log_user_in(
   conn: &Connection,
   uid: UserId,
 ) -> Result<UserResponse, error::Error> {
+  if dbfun::approval_pending(conn, uid)? {
+    return Ok(UserResponse::UrpRegistrationPending);
+  }
+  if dbfun::must_change_password(conn, uid)? {
+    return Ok(UserResponse::UrpPasswordChangeRequired);
+  }
+  dbfun::record_login(conn, uid)?;
   let mut ld = dbfun::login_data(&conn, uid)?;
   let data = (callbacks.extra_login_data)(&conn, ld.userid)?;
   ld.data = data.map(|x| x.to_string());
@@ -92,6 +124,58 @@ pub fn log_user_in(
   Ok(UserResponse::UrpLoggedIn(ld))
 }
 
+fn verification_blocks_login(config: &Config) -> bool {
+  config.send_emails && config.email_verification == Some(EmailVerification::BlockLogin)
+}
+
+// log_user_in, unless the email verification policy says to verify first.
+fn log_verified_user_in(
+  tokener: &mut dyn Tokener,
+  config: &Config,
+  callbacks: &mut Callbacks,
+  conn: &Connection,
+  uid: UserId,
+) -> Result<UserResponse, error::Error> {
+  if verification_blocks_login(config) && dbfun::email_verified(conn, uid)?.is_none() {
+    return Ok(UserResponse::UrpEmailNotVerified);
+  }
+  log_user_in(tokener, callbacks, conn, uid)
+}
+
+// send a link to the user's current email, unless one went out too recently.
+fn send_email_verification(
+  conn: &Connection,
+  config: &Config,
+  user: &User,
+) -> Result<UserResponse, error::Error> {
+  if dbfun::email_verified(conn, user.id)?.is_some() {
+    return Ok(UserResponse::UrpEmailAlreadyVerified);
+  }
+  if !config.send_emails {
+    return Err("verification emails are disabled".into());
+  }
+  if let Some(sent) = dbfun::last_email_verify(conn, user.id)? {
+    let next = sent
+      + config
+        .registration_resend_interval_ms
+        .unwrap_or(DEFAULT_REGISTRATION_RESEND_INTERVAL_MS);
+    if now()? < next {
+      return Ok(UserResponse::UrpEmailVerificationTooSoon(next));
+    }
+  }
+  let token = Uuid::new_v4();
+  dbfun::add_email_verify(conn, user.id, user.email.as_str(), token)?;
+  email::send_email_verification(
+    config.appname.as_str(),
+    config.emaildomain.as_str(),
+    config.mainsite.as_str(),
+    user.email.as_str(),
+    user.name.as_str(),
+    token.to_string().as_str(),
+  )?;
+  Ok(UserResponse::UrpEmailVerificationSent)
+}
+
 pub async fn user_interface(
   conn: &Connection,
   tokener: &mut dyn Tokener,
@@ -100,6 +184,8 @@ pub async fn user_interface(
   user_uri_path: Option<String>,
   msg: UserRequest,
 ) -> Result<UserResponse, error::Error> {
+  // admins can override config values at runtime.
+  let config = &dbfun::current_config(conn, config)?;
   match msg {
     UserRequest::UrqRegister(rd) => {
       if !config.open_registration {
@@ -116,6 +202,14 @@ pub async fn user_interface(
               if rd.pwd.trim() == "" {
                 return Ok(UserResponse::UrpBlankPassword);
               }
+              if let Some(r) = check_email_domain(config, rd.email.as_str())? {
+                return Ok(r);
+              }
+              if rd.email != user.email
+                && dbfun::email_in_use(conn, rd.email.as_str(), Some(user.id))?
+              {
+                return Ok(UserResponse::UrpEmailInUse);
+              }
 
               user.email = rd.email;
 
@@ -140,6 +234,7 @@ pub async fn user_interface(
                   rd.uid.as_str(),
                   reg_key.as_str(),
                 )?;
+                dbfun::set_registration_sent(conn, user.id)?;
                 // notify the admin.
                 email::send_registration_notification(
                   config.appname.as_str(),
@@ -171,6 +266,15 @@ pub async fn user_interface(
           if rd.pwd.trim() == "" {
             return Ok(UserResponse::UrpBlankPassword);
           }
+          if let Err(e) = dbfun::check_username(conn, config, rd.uid.as_str(), None)? {
+            return Ok(UserResponse::UrpInvalidUserName(e));
+          }
+          if let Some(r) = check_email_domain(config, rd.email.as_str())? {
+            return Ok(r);
+          }
+          if dbfun::email_in_use(conn, rd.email.as_str(), None)? {
+            return Ok(UserResponse::UrpEmailInUse);
+          }
 
           // are we doing remote registration?
           if config.remote_registration && rd.remote_url != "" {
@@ -218,6 +322,9 @@ pub async fn user_interface(
                 cookie,
                 &mut callbacks.on_new_user,
               )?;
+              if config.registration_approval {
+                dbfun::set_approval_pending(conn, uid, true)?;
+              }
 
               log_user_in(tokener, callbacks, &conn, uid)
             } else {
@@ -225,16 +332,23 @@ pub async fn user_interface(
             }
           } else {
             // get email from 'data'.
-            let registration_key = Uuid::new_v4().to_string();
+            // with a verification policy, the user gets in now and verifies later.
+            let verify_later = config.send_emails
+              && matches!(
+                config.email_verification,
+                Some(EmailVerification::Restricted) | Some(EmailVerification::NotRequired)
+              );
+            // only stored, and sent, when the user has to register by email.
+            let registration_key = if config.send_emails && !verify_later {
+              Some(Uuid::new_v4().to_string())
+            } else {
+              // Instant registration for send_emails = false.
+              None
+            };
             let uid = dbfun::new_user(
               &conn,
               &rd,
-              if config.send_emails {
-                Some(registration_key.clone().to_string())
-              } else {
-                // Instant registration for send_emails = false.
-                None
-              },
+              registration_key.clone(),
               None,
               false, // NOT admin by default.
               None,
@@ -244,8 +358,30 @@ pub async fn user_interface(
               None,
               &mut callbacks.on_new_user,
             )?;
+            if config.registration_approval {
+              dbfun::set_approval_pending(conn, uid, true)?;
+            }
 
-            if config.send_emails {
+            if verify_later {
+              // the account exists now, so a failed email shouldn't fail the registration;
+              // the user can ask for another verification email.
+              let user = dbfun::read_user_by_id(conn, uid)?;
+              if let Err(e) = send_email_verification(conn, config, &user) {
+                warn!("verification email to {} failed: {:?}", rd.email, e);
+              }
+              // notify the admin.  there's no registration link to include.
+              if let Err(e) = email::send_registration_notification(
+                config.appname.as_str(),
+                config.emaildomain.as_str(),
+                config.admin_email.as_str(),
+                rd.email.as_str(),
+                rd.uid.as_str(),
+                "",
+              ) {
+                warn!("registration notification for {} failed: {:?}", rd.uid, e);
+              }
+              log_user_in(tokener, callbacks, conn, uid)
+            } else if let Some(registration_key) = registration_key {
               // send a registration email.
               email::send_registration(
                 config.appname.as_str(),
@@ -255,6 +391,7 @@ pub async fn user_interface(
                 rd.uid.as_str(),
                 registration_key.as_str(),
               )?;
+              dbfun::set_registration_sent(conn, uid)?;
 
               // notify the admin.
               email::send_registration_notification(
@@ -283,6 +420,10 @@ pub async fn user_interface(
           Err(e) => return Err(e),
           Ok(Some(i)) => i,
         };
+      // only an invite that went out by email says anything about the address.
+      let emailed = dbfun::userinvite_emailed(conn, rsvp.invite.as_str())?;
+      // with registration_approval, only an admin's invite stands in for approval.
+      let admin_invite = dbfun::read_user_by_id(conn, invite.creator)?.admin;
 
       // uid already exists?
       match dbfun::read_user_by_name(&conn, rsvp.uid.as_str()) {
@@ -297,8 +438,17 @@ pub async fn user_interface(
             // don't distinguish between bad user id and bad pwd
             // maybe would ok for one-time use invites.
             Ok(UserResponse::UrpInvalidUserOrPwd)
+          } else if dbfun::user_deleted(conn, userdata.id)? {
+            Ok(UserResponse::UrpInvalidUserOrPwd)
           } else if !userdata.active {
             Ok(UserResponse::UrpAccountDeactivated)
+          } else if let Some(s) = dbfun::read_suspension(conn, userdata.id)? {
+            Ok(UserResponse::UrpAccountSuspended(SuspensionNotice {
+              reason: s.reason,
+              until: s.until,
+            }))
+          } else if !invite_email_ok(&invite, userdata.email.as_str()) {
+            Ok(UserResponse::UrpInviteEmailMismatch)
           } else {
             match userdata.registration_key {
               Some(_reg_key) => {
@@ -310,12 +460,26 @@ pub async fn user_interface(
               }
               None => (),
             }
+            if admin_invite {
+              dbfun::set_approval_pending(conn, userdata.id, false)?;
+            }
             // password matches, account active, already registered
 
-            // delete the invite.
-            dbfun::remove_userinvite(&conn, &rsvp.invite.as_str())?;
+            if let Some(org) = invite.org {
+              dbfun::add_org_member(conn, org, userdata.id, OrgRole::OrMember)?;
+            }
+
+            // count the use; the invite goes away once it's used up.
+            dbfun::use_userinvite(conn, rsvp.invite.as_str(), userdata.id)?;
+            // an emailed invite verifies the address it was sent to.
+            if emailed
+              && invite.email.as_deref() == Some(userdata.email.as_str())
+              && dbfun::email_verified(conn, userdata.id)?.is_none()
+            {
+              dbfun::set_email_verified(conn, userdata.id)?;
+            }
             // log in.
-            log_user_in(tokener, callbacks, &conn, userdata.id)
+            log_verified_user_in(tokener, config, callbacks, conn, userdata.id)
           }
         }
         Err(_) => {
@@ -328,6 +492,18 @@ pub async fn user_interface(
           if rsvp.pwd.trim() == "" {
             return Ok(UserResponse::UrpBlankPassword);
           }
+          if let Err(e) = dbfun::check_username(conn, config, rsvp.uid.as_str(), None)? {
+            return Ok(UserResponse::UrpInvalidUserName(e));
+          }
+          if !invite_email_ok(&invite, rsvp.email.as_str()) {
+            return Ok(UserResponse::UrpInviteEmailMismatch);
+          }
+          if let Some(r) = check_email_domain(config, rsvp.email.as_str())? {
+            return Ok(r);
+          }
+          if dbfun::email_in_use(conn, rsvp.email.as_str(), None)? {
+            return Ok(UserResponse::UrpEmailInUse);
+          }
 
           let rd = RegistrationData {
             uid: rsvp.uid.clone(),
@@ -351,8 +527,23 @@ pub async fn user_interface(
             &mut callbacks.on_new_user,
           )?;
 
-          // delete the invite.
-          dbfun::remove_userinvite(&conn, &rsvp.invite.as_str())?;
+          if config.registration_approval && !admin_invite {
+            dbfun::set_approval_pending(conn, uid, true)?;
+          }
+
+          if let Some(org) = invite.org {
+            dbfun::add_org_member(conn, org, uid, OrgRole::OrMember)?;
+          }
+
+          // count the use; the invite goes away once it's used up.
+          dbfun::use_userinvite(conn, rsvp.invite.as_str(), uid)?;
+          // an emailed invite verifies the address it was sent to.
+          if emailed && invite.email.as_deref() == Some(rsvp.email.as_str()) {
+            dbfun::set_email_verified(conn, uid)?;
+          } else if config.email_verification.is_some() && config.send_emails {
+            let user = dbfun::read_user_by_id(conn, uid)?;
+            send_email_verification(conn, config, &user)?;
+          }
 
           // notify the admin.
           if config.send_emails {
@@ -375,7 +566,7 @@ pub async fn user_interface(
           }
 
           // respond with login.
-          log_user_in(tokener, callbacks, &conn, uid)
+          log_verified_user_in(tokener, config, callbacks, conn, uid)
         }
       }
     }
@@ -387,7 +578,11 @@ pub async fn user_interface(
       }
     }
     UserRequest::UrqLogin(login) => {
-      let userdata = dbfun::read_user_by_name(&conn, login.uid.as_str())?;
+      let userdata =
+        dbfun::read_user_by_login_id(conn, config.login_identifier, login.uid.as_str())?;
+      if dbfun::user_deleted(conn, userdata.id)? {
+        return Ok(UserResponse::UrpInvalidUserOrPwd);
+      }
       match userdata.registration_key {
         Some(_reg_key) => Ok(UserResponse::UrpUnregisteredUser),
         None => {
@@ -400,8 +595,13 @@ pub async fn user_interface(
             {
               // don't distinguish between bad user id and bad pwd!
               Ok(UserResponse::UrpInvalidUserOrPwd)
+            } else if let Some(s) = dbfun::read_suspension(conn, userdata.id)? {
+              Ok(UserResponse::UrpAccountSuspended(SuspensionNotice {
+                reason: s.reason,
+                until: s.until,
+              }))
             } else {
-              log_user_in(tokener, callbacks, &conn, userdata.id)
+              log_verified_user_in(tokener, config, callbacks, conn, userdata.id)
             }
           } else {
             Ok(UserResponse::UrpAccountDeactivated)
@@ -410,12 +610,22 @@ pub async fn user_interface(
       }
     }
     UserRequest::UrqLogout => {
+      // impersonation tokens don't outlive the session.
+      if let Some(token) = tokener.get() {
+        if let Some((uid, admin, _)) = dbfun::end_impersonation(conn, token)? {
+          dbfun::add_audit(conn, Some(admin), Some(uid), "end impersonation", None)?;
+        }
+      }
       tokener.remove();
 
       Ok(UserResponse::UrpLoggedOut)
     }
     UserRequest::UrqResetPassword(reset_password) => {
-      let userdata = dbfun::read_user_by_name(&conn, reset_password.uid.as_str())?;
+      let userdata =
+        dbfun::read_user_by_login_id(conn, config.login_identifier, reset_password.uid.as_str())?;
+      if dbfun::user_deleted(conn, userdata.id)? {
+        return Ok(UserResponse::UrpInvalidUserOrPwd);
+      }
       match userdata.registration_key {
         Some(_reg_key) => Ok(UserResponse::UrpUnregisteredUser),
         None => {
@@ -440,8 +650,61 @@ pub async fn user_interface(
         }
       }
     }
+    UserRequest::UrqResendRegistration(rr) => {
+      let mut userdata =
+        dbfun::read_user_by_login_id(conn, config.login_identifier, rr.uid.as_str())?;
+      if dbfun::user_deleted(conn, userdata.id)? || dbfun::is_phantom(&userdata) {
+        return Ok(UserResponse::UrpInvalidUserOrPwd);
+      }
+      let reg_key = match userdata.registration_key {
+        Some(ref k) => k.clone(),
+        None => return Ok(UserResponse::UrpUserExists),
+      };
+      if !config.send_emails {
+        return Err("registration emails are disabled".into());
+      }
+      if let Some(sent) = dbfun::registration_sent(conn, userdata.id)? {
+        let next = sent
+          + config
+            .registration_resend_interval_ms
+            .unwrap_or(DEFAULT_REGISTRATION_RESEND_INTERVAL_MS);
+        if now()? < next {
+          return Ok(UserResponse::UrpRegistrationResendTooSoon(next));
+        }
+      }
+      let reg_key = if rr.new_key {
+        Uuid::new_v4().to_string()
+      } else {
+        reg_key
+      };
+      email::send_registration(
+        config.appname.as_str(),
+        config.emaildomain.as_str(),
+        config.mainsite.as_str(),
+        userdata.email.as_str(),
+        userdata.name.as_str(),
+        reg_key.as_str(),
+      )?;
+      // only replace the old key once the new one is on its way.
+      if rr.new_key {
+        userdata.registration_key = Some(reg_key);
+        dbfun::update_user(conn, &userdata)?;
+      }
+      dbfun::set_registration_sent(conn, userdata.id)?;
+      Ok(UserResponse::UrpRegistrationSent)
+    }
+    UserRequest::UrqSendEmailVerification(uid) => {
+      let userdata = dbfun::read_user_by_login_id(conn, config.login_identifier, uid.as_str())?;
+      if dbfun::user_deleted(conn, userdata.id)? {
+        return Ok(UserResponse::UrpInvalidUserOrPwd);
+      }
+      if userdata.registration_key.is_some() {
+        return Ok(UserResponse::UrpUnregisteredUser);
+      }
+      send_email_verification(conn, config, &userdata)
+    }
     UserRequest::UrqSetPassword(set_password) => {
-      let mut userdata = dbfun::read_user_by_name(&conn, set_password.uid.as_str())?;
+      let mut userdata = dbfun::resolve_user_by_name(conn, set_password.uid.as_str())?;
       match userdata.registration_key {
         Some(_reg_key) => Ok(UserResponse::UrpUnregisteredUser),
         None => {
@@ -457,11 +720,79 @@ pub async fn user_interface(
             );
             dbfun::remove_newpassword(&conn, userdata.id, set_password.reset_key)?;
             dbfun::update_user(&conn, &userdata)?;
+            dbfun::set_must_change_password(conn, userdata.id, false)?;
+            // the reset link went to their email.
+            if dbfun::email_verified(conn, userdata.id)?.is_none() {
+              dbfun::set_email_verified(conn, userdata.id)?;
+            }
             Ok(UserResponse::UrpSetPasswordAck)
           }
         }
       }
     }
+    UserRequest::UrqChangePasswordLogin(cpl) => {
+      let userdata = dbfun::read_user_by_login_id(conn, config.login_identifier, cpl.uid.as_str())?;
+      if dbfun::user_deleted(conn, userdata.id)? {
+        return Ok(UserResponse::UrpInvalidUserOrPwd);
+      }
+      if !userdata.active {
+        return Ok(UserResponse::UrpAccountDeactivated);
+      }
+      if cpl.newpwd.trim() == "" {
+        return Ok(UserResponse::UrpBlankPassword);
+      }
+      if !dbfun::check_password(conn, userdata.id, cpl.oldpwd.as_str())? {
+        return Ok(UserResponse::UrpInvalidUserOrPwd);
+      }
+      if let Some(s) = dbfun::read_suspension(conn, userdata.id)? {
+        return Ok(UserResponse::UrpAccountSuspended(SuspensionNotice {
+          reason: s.reason,
+          until: s.until,
+        }));
+      }
+      dbfun::change_password(
+        conn,
+        userdata.id,
+        &ChangePassword {
+          oldpwd: cpl.oldpwd.clone(),
+          newpwd: cpl.newpwd.clone(),
+        },
+      )?;
+      log_verified_user_in(tokener, config, callbacks, conn, userdata.id)
+    }
+    UserRequest::UrqEndImpersonation => {
+      let token = match tokener.get() {
+        Some(token) => token,
+        None => return Ok(UserResponse::UrpNotLoggedIn),
+      };
+      match dbfun::end_impersonation(conn, token)? {
+        None => Err("not impersonating".into()),
+        Some((uid, admin, admin_token)) => {
+          dbfun::add_audit(conn, Some(admin), Some(uid), "end impersonation", None)?;
+          // back to the admin's session, if it's still good.
+          match admin_token.map(|t| {
+            dbfun::read_user_by_token_api(
+              conn,
+              t,
+              config.login_token_expiration_ms,
+              config.regen_login_tokens,
+            )
+            .map(|u| (t, u))
+          }) {
+            Some(Ok((t, u))) if u.id == admin => {
+              tokener.set(t)?;
+              let mut ld = dbfun::login_data(conn, admin)?;
+              ld.data = (callbacks.extra_login_data)(conn, admin)?.map(|x| x.to_string());
+              Ok(UserResponse::UrpLoggedIn(ld))
+            }
+            _ => {
+              tokener.remove();
+              Ok(UserResponse::UrpLoggedOut)
+            }
+          }
+        }
+      }
+    }
     UserRequest::UrqAuthedRequest(ar) => {
       // are we logged in?
       match tokener.get() {
@@ -476,8 +807,10 @@ pub async fn user_interface(
           ) {
             Err(_e) => Ok(UserResponse::UrpInvalidUserOrPwd),
             Ok(userdata) => {
+              let impersonator = dbfun::token_impersonator(&conn, token)?;
               // finally!  processing messages as logged in user.
-              user_interface_loggedin(&config, &conn, callbacks, userdata.id, &ar).await
+              user_interface_loggedin(config, &conn, callbacks, userdata.id, impersonator, &ar)
+                .await
             }
           }
         }
@@ -491,14 +824,49 @@ pub async fn user_interface_loggedin(
   conn: &Connection,
   callbacks: &mut Callbacks,
   uid: UserId,
+  // set for admin impersonation sessions, which can't change credentials.
+  impersonator: Option<UserId>,
   msg: &AuthedRequest,
 ) -> Result<UserResponse, error::Error> {
+  let config = &dbfun::current_config(conn, config)?;
+  if impersonator.is_some() {
+    match msg {
+      AuthedRequest::AthChangePassword(_)
+      | AuthedRequest::AthChangeEmail(_)
+      | AuthedRequest::AthChangeUserName(_)
+      | AuthedRequest::AthChangeRemoteUrl(_)
+      | AuthedRequest::AthCancelEmailChange(_)
+      | AuthedRequest::AthSendEmailVerification
+      | AuthedRequest::AthDeleteAccount(_) => return Ok(UserResponse::UrpImpersonationDenied),
+      _ => (),
+    }
+  }
+  match msg {
+    AuthedRequest::AthGetInvite(_)
+    | AuthedRequest::AthChangeEmail(_)
+    | AuthedRequest::AthCreateOrg(_)
+    | AuthedRequest::AthOrgRequest(_)
+    | AuthedRequest::AthGroupRequest(GroupRequest::GrqCreateGroup(_))
+    | AuthedRequest::AthGroupRequest(GroupRequest::GrqAddMember(_))
+    | AuthedRequest::AthGroupRequest(GroupRequest::GrqAddSubgroup(_))
+      if dbfun::email_restricted(conn, config, uid)? =>
+    {
+      return Ok(UserResponse::UrpEmailNotVerified);
+    }
+    _ => (),
+  }
   match msg {
     AuthedRequest::AthChangePassword(cp) => {
       dbfun::change_password(&conn, uid, cp)?;
       Ok(UserResponse::UrpChangedPassword)
     }
     AuthedRequest::AthChangeEmail(cp) => {
+      if let Some(r) = check_email_domain(config, cp.email.as_str())? {
+        return Ok(r);
+      }
+      if dbfun::email_in_use(conn, cp.email.as_str(), Some(uid))? {
+        return Ok(UserResponse::UrpEmailInUse);
+      }
       let (name, token) = dbfun::change_email(&conn, uid, cp.clone())?;
       // send a confirmation email.
       if config.send_emails {
@@ -514,6 +882,13 @@ pub async fn user_interface_loggedin(
 
       Ok(UserResponse::UrpChangedEmail)
     }
+    AuthedRequest::AthChangeUserName(cun) => {
+      if let Err(e) = dbfun::check_username(conn, config, cun.name.as_str(), Some(uid))? {
+        return Ok(UserResponse::UrpInvalidUserName(e));
+      }
+      let name = dbfun::change_username(conn, uid, cun)?;
+      Ok(UserResponse::UrpChangedUserName(name))
+    }
     AuthedRequest::AthChangeRemoteUrl(cp) => {
       let uresp = dbfun::change_remote_url(&conn, uid, "user".to_string(), &cp).await?;
 
@@ -538,27 +913,329 @@ pub async fn user_interface_loggedin(
       }
     }
     AuthedRequest::AthGetInvite(gi) => {
-      if config.non_admin_invite {
-        let invite_key = Uuid::new_v4();
+      let user = dbfun::read_user_by_id(conn, uid)?;
+      if let Some(org) = gi.org {
+        // org owners and admins can always invite into their org.
+        match org_role(conn, org, &user)? {
+          Some(OrgRole::OrOwner) | Some(OrgRole::OrAdmin) => (),
+          _ => return Ok(UserResponse::UrpOrgAccessDenied),
+        }
+      } else if !config.non_admin_invite {
+        return Err("non-admin user invites are disabled!".into());
+      }
 
-        dbfun::add_userinvite(
-          &conn,
-          invite_key.clone(),
-          gi.email.clone(),
-          uid,
-          gi.data.clone(),
-        )?;
-        Ok(UserResponse::UrpInvite(UserInvite {
-          email: None,
-          token: invite_key.to_string(),
-          url: format!("{}/invite/{}", config.mainsite, invite_key.to_string()),
-          creator: uid,
-          data: gi.data.clone(),
+      // quotas don't apply to admins.
+      if !user.admin {
+        let qs = dbfun::read_invite_quota(conn, config, uid)?;
+        if qs.quota.revoked {
+          return Ok(UserResponse::UrpInvitesRevoked);
+        }
+        // every use of the invite counts against the quota.
+        if let Some(remaining) = qs.remaining {
+          if gi.max_uses.unwrap_or(1) > remaining {
+            return Ok(UserResponse::UrpInviteQuotaExceeded);
+          }
+        }
+      }
+
+      let invite = make_invite(conn, config, uid, gi)?;
+      dbfun::record_invite_issue(conn, uid, gi.max_uses.unwrap_or(1))?;
+      Ok(UserResponse::UrpInvite(invite))
+    }
+    AuthedRequest::AthSendEmailVerification => {
+      let user = dbfun::read_user_by_id(conn, uid)?;
+      send_email_verification(conn, config, &user)
+    }
+    AuthedRequest::AthGetPendingEmailChanges => Ok(UserResponse::UrpPendingEmailChanges(
+      dbfun::read_newemails(conn, uid, config.email_token_expiration_ms)?,
+    )),
+    AuthedRequest::AthCancelEmailChange(email) => {
+      if dbfun::cancel_newemails(conn, uid, Some(email.as_str()))? == 0 {
+        return Err("no pending change to that email".into());
+      }
+      Ok(UserResponse::UrpEmailChangeCancelled(email.clone()))
+    }
+    AuthedRequest::AthGetInviteQuota => Ok(UserResponse::UrpInviteQuota(dbfun::read_invite_quota(
+      conn, config, uid,
+    )?)),
+    AuthedRequest::AthGetInvites => Ok(UserResponse::UrpInvites(dbfun::read_userinvites(
+      conn,
+      config.mainsite.as_str(),
+      config.invite_token_expiration_ms,
+      Some(uid),
+    )?)),
+    AuthedRequest::AthRevokeInvite(token) => {
+      if dbfun::revoke_userinvite(conn, token.as_str(), Some(uid))? {
+        Ok(UserResponse::UrpInviteRevoked(token.clone()))
+      } else {
+        Err("invite not found".into())
+      }
+    }
+    AuthedRequest::AthCreateOrg(name) => {
+      let org = dbfun::new_org(conn, name.as_str(), uid)?;
+      Ok(UserResponse::UrpOrg(dbfun::read_org(conn, org)?))
+    }
+    AuthedRequest::AthLeaveOrg(org) => match dbfun::read_org_role(conn, *org, uid)? {
+      None => Err(format!("not a member of org {}", org).into()),
+      Some(OrgRole::OrOwner) => Err("the org owner can't leave; transfer ownership first".into()),
+      Some(_) => {
+        dbfun::remove_org_member(conn, *org, uid)?;
+        Ok(UserResponse::UrpLeftOrg(*org))
+      }
+    },
+    AuthedRequest::AthOrgRequest(oreq) => {
+      let user = dbfun::read_user_by_id(conn, uid)?;
+      org_interface(conn, &user, oreq)
+    }
+    AuthedRequest::AthGetProfile => Ok(UserResponse::UrpProfile(dbfun::read_profile(conn, uid)?)),
+    AuthedRequest::AthUpdateProfile(profile) => {
+      // users can only update their own profile.
+      let mut profile = profile.clone();
+      profile.userid = uid;
+      let errors = dbfun::validate_profile(&config.profile_fields, &profile);
+      if errors.is_empty() {
+        dbfun::save_profile(conn, &profile)?;
+        Ok(UserResponse::UrpProfile(dbfun::read_profile(conn, uid)?))
+      } else {
+        Ok(UserResponse::UrpInvalidProfile(errors))
+      }
+    }
+    AuthedRequest::AthDeleteAccount(da) => {
+      if !dbfun::check_password(conn, uid, da.pwd.as_str())? {
+        return Ok(UserResponse::UrpInvalidUserOrPwd);
+      }
+      if dbfun::is_last_admin(conn, uid)? {
+        return Ok(UserResponse::UrpLastAdmin);
+      }
+      let userdata = dbfun::read_user_by_id(conn, uid)?;
+      let purge_date = match config.delete_grace_period_ms {
+        Some(gp) => {
+          dbfun::soft_delete_user(conn, uid)?;
+          Some(now()? + gp)
+        }
+        None => {
+          conn.execute("begin transaction", params!())?;
+          match (callbacks.on_delete_user)(conn, uid).and_then(|ok| {
+            if ok {
+              dbfun::delete_user(conn, uid)?;
+            }
+            Ok(ok)
+          }) {
+            Ok(true) => {
+              conn.execute("commit", params!())?;
+              None
+            }
+            Ok(false) => {
+              conn.execute("rollback", params!())?;
+              return Ok(UserResponse::UrpAccountNotDeleted);
+            }
+            Err(e) => {
+              conn.execute("rollback", params!())?;
+              return Err(e);
+            }
+          }
+        }
+      };
+      if config.send_emails {
+        if let Err(e) = email::send_account_deleted(
+          config.appname.as_str(),
+          config.emaildomain.as_str(),
+          userdata.email.as_str(),
+          userdata.name.as_str(),
+          purge_date,
+        ) {
+          // the account is gone either way.
+          error!("error sending account deletion email: {:?}", e);
+        }
+      }
+      match purge_date {
+        Some(pd) => Ok(UserResponse::UrpAccountDeletionScheduled(pd)),
+        None => Ok(UserResponse::UrpAccountDeleted),
+      }
+    }
+    AuthedRequest::AthExportData => Ok(UserResponse::UrpDataExport(export_user_data(
+      conn, callbacks, uid,
+    )?)),
+    AuthedRequest::AthGetProfileFields => Ok(UserResponse::UrpProfileFields(
+      config.profile_fields.clone(),
+    )),
+    AuthedRequest::AthGroupRequest(greq) => Ok(UserResponse::UrpGroupResponse(group_interface(
+      conn, uid, false, greq,
+    )?)),
+  }
+}
+
+// non-admins can only manage groups they own.
+fn group_owner_check(
+  conn: &Connection,
+  uid: UserId,
+  admin: bool,
+  group: GroupId,
+) -> Result<bool, error::Error> {
+  Ok(admin || dbfun::read_group(conn, group)?.owner == Some(uid))
+}
+
+pub fn group_interface(
+  conn: &Connection,
+  uid: UserId,
+  admin: bool,
+  msg: &GroupRequest,
+) -> Result<GroupResponse, error::Error> {
+  match msg {
+    GroupRequest::GrqCreateGroup(name) => {
+      let group = dbfun::new_group(conn, name.as_str(), uid)?;
+      Ok(GroupResponse::GrpGroup(dbfun::read_group_info(
+        conn, group,
+      )?))
+    }
+    GroupRequest::GrqRenameGroup(rg) => {
+      if !group_owner_check(conn, uid, admin, rg.group)? {
+        return Ok(GroupResponse::GrpAccessDenied);
+      }
+      dbfun::rename_group(conn, rg.group, rg.name.as_str())?;
+      Ok(GroupResponse::GrpGroup(dbfun::read_group_info(
+        conn, rg.group,
+      )?))
+    }
+    GroupRequest::GrqDeleteGroup(group) => {
+      if !group_owner_check(conn, uid, admin, *group)? {
+        return Ok(GroupResponse::GrpAccessDenied);
+      }
+      dbfun::delete_group(conn, *group)?;
+      Ok(GroupResponse::GrpGroupDeleted(*group))
+    }
+    GroupRequest::GrqAddMember(gu) => {
+      if !group_owner_check(conn, uid, admin, gu.group)? {
+        return Ok(GroupResponse::GrpAccessDenied);
+      }
+      dbfun::add_group_member(conn, gu.group, gu.userid)?;
+      Ok(GroupResponse::GrpGroup(dbfun::read_group_info(
+        conn, gu.group,
+      )?))
+    }
+    GroupRequest::GrqRemoveMember(gu) => {
+      if !group_owner_check(conn, uid, admin, gu.group)? {
+        return Ok(GroupResponse::GrpAccessDenied);
+      }
+      dbfun::remove_group_member(conn, gu.group, gu.userid)?;
+      Ok(GroupResponse::GrpGroup(dbfun::read_group_info(
+        conn, gu.group,
+      )?))
+    }
+    GroupRequest::GrqAddSubgroup(sg) => {
+      // members of the subgroup get the parent's access, so own both.
+      if !group_owner_check(conn, uid, admin, sg.group)?
+        || !group_owner_check(conn, uid, admin, sg.subgroup)?
+      {
+        return Ok(GroupResponse::GrpAccessDenied);
+      }
+      dbfun::add_subgroup(conn, sg.group, sg.subgroup)?;
+      Ok(GroupResponse::GrpGroup(dbfun::read_group_info(
+        conn, sg.group,
+      )?))
+    }
+    GroupRequest::GrqRemoveSubgroup(sg) => {
+      if !group_owner_check(conn, uid, admin, sg.group)? {
+        return Ok(GroupResponse::GrpAccessDenied);
+      }
+      dbfun::remove_subgroup(conn, sg.group, sg.subgroup)?;
+      Ok(GroupResponse::GrpGroup(dbfun::read_group_info(
+        conn, sg.group,
+      )?))
+    }
+    GroupRequest::GrqGetGroup(group) => {
+      // members can see the groups they're in.
+      if group_owner_check(conn, uid, admin, *group)? || dbfun::is_group_member(conn, uid, *group)?
+      {
+        Ok(GroupResponse::GrpGroup(dbfun::read_group_info(
+          conn, *group,
+        )?))
+      } else {
+        Ok(GroupResponse::GrpAccessDenied)
+      }
+    }
+    GroupRequest::GrqGetGroups => Ok(GroupResponse::GrpGroups(dbfun::read_groups(
+      conn,
+      if admin { None } else { Some(uid) },
+    )?)),
+    GroupRequest::GrqGetUserGroups(guid) => {
+      if admin || *guid == uid {
+        Ok(GroupResponse::GrpUserGroups(UserGroups {
+          userid: *guid,
+          groups: dbfun::read_user_groups(conn, *guid)?,
         }))
       } else {
-        Err("non-admin user invites are disabled!".into())
+        Ok(GroupResponse::GrpAccessDenied)
+      }
+    }
+  }
+}
+
+// global admins manage every org as if they owned it.
+fn org_role(conn: &Connection, org: OrgId, user: &User) -> Result<Option<OrgRole>, error::Error> {
+  if user.admin {
+    Ok(Some(OrgRole::OrOwner))
+  } else {
+    dbfun::read_org_role(conn, org, user.id)
+  }
+}
+
+fn org_members(conn: &Connection, org: OrgId) -> Result<UserResponse, error::Error> {
+  Ok(UserResponse::UrpOrgMembers(OrgMembers {
+    org,
+    members: dbfun::read_org_members(conn, org)?,
+  }))
+}
+
+pub fn org_interface(
+  conn: &Connection,
+  user: &User,
+  msg: &OrgRequest,
+) -> Result<UserResponse, error::Error> {
+  match msg {
+    OrgRequest::OrqGetMembers(org) => match org_role(conn, *org, user)? {
+      Some(_) => org_members(conn, *org),
+      None => Ok(UserResponse::UrpOrgAccessDenied),
+    },
+    OrgRequest::OrqSetMemberRole(smr) => {
+      // ownership changes go through OrqTransferOwnership.
+      if smr.role == OrgRole::OrOwner {
+        return Ok(UserResponse::UrpOrgAccessDenied);
+      }
+      match (
+        org_role(conn, smr.org, user)?,
+        dbfun::read_org_role(conn, smr.org, smr.userid)?,
+      ) {
+        (_, None) => Err(format!("user {} is not a member of org {}", smr.userid, smr.org).into()),
+        (_, Some(OrgRole::OrOwner)) => Ok(UserResponse::UrpOrgAccessDenied),
+        // org admins can promote plain members, but only the owner changes admins.
+        (Some(OrgRole::OrOwner), _) | (Some(OrgRole::OrAdmin), Some(OrgRole::OrMember)) => {
+          dbfun::set_org_member_role(conn, smr.org, smr.userid, smr.role)?;
+          org_members(conn, smr.org)
+        }
+        _ => Ok(UserResponse::UrpOrgAccessDenied),
       }
     }
+    OrgRequest::OrqRemoveMember(ou) => match (
+      org_role(conn, ou.org, user)?,
+      dbfun::read_org_role(conn, ou.org, ou.userid)?,
+    ) {
+      (_, None) => Err(format!("user {} is not a member of org {}", ou.userid, ou.org).into()),
+      (_, Some(OrgRole::OrOwner)) => Ok(UserResponse::UrpOrgAccessDenied),
+      // org admins can remove plain members, but not other admins.
+      (Some(OrgRole::OrOwner), _) | (Some(OrgRole::OrAdmin), Some(OrgRole::OrMember)) => {
+        dbfun::remove_org_member(conn, ou.org, ou.userid)?;
+        Ok(UserResponse::UrpOrgMemberRemoved(ou.clone()))
+      }
+      _ => Ok(UserResponse::UrpOrgAccessDenied),
+    },
+    OrgRequest::OrqTransferOwnership(ou) => match org_role(conn, ou.org, user)? {
+      Some(OrgRole::OrOwner) => {
+        dbfun::transfer_org(conn, ou.org, ou.userid)?;
+        org_members(conn, ou.org)
+      }
+      _ => Ok(UserResponse::UrpOrgAccessDenied),
+    },
   }
 }
 
@@ -573,15 +1250,20 @@ pub fn admin_interface_check(
     None => Ok(AdminResponse::ArpNotLoggedIn),
     Some(token) => {
       let conn = dbfun::connection_open(config.db.as_path())?;
+      // admin_interface gets the plain config, for ArqResetSettings.
+      let current = dbfun::current_config(&conn, config)?;
       match dbfun::read_user_by_token_api(
         &conn,
         token,
-        config.login_token_expiration_ms,
-        config.regen_login_tokens,
+        current.login_token_expiration_ms,
+        current.regen_login_tokens,
       ) {
         Err(_e) => Ok(AdminResponse::ArpInvalidUserOrPassword),
         Ok(userdata) => {
           if userdata.admin {
+            if let AdminRequest::ArqImpersonate(uid) = msg {
+              return impersonate(tokener, &conn, config, callbacks, &userdata, token, uid);
+            }
             // finally!  processing messages as logged in user.
             admin_interface(&conn, &config, &userdata, callbacks, &msg)
           } else {
@@ -593,6 +1275,496 @@ pub fn admin_interface_check(
   }
 }
 
+// log the admin in as uid, with a short lived token that remembers the admin's own token.
+fn impersonate(
+  tokener: &mut dyn Tokener,
+  conn: &Connection,
+  config: &Config,
+  callbacks: &mut Callbacks,
+  admin: &User,
+  admin_token: Uuid,
+  uid: UserId,
+) -> Result<AdminResponse, error::Error> {
+  let target = dbfun::read_user_by_id(conn, uid)?;
+  if target.id == admin.id || target.admin {
+    return Err("can't impersonate admin users".into());
+  }
+  if dbfun::user_deleted(conn, uid)? {
+    return Err("can't impersonate deleted users".into());
+  }
+
+  let token = Uuid::new_v4();
+  let expiredate = now()?
+    + config
+      .impersonation_expiration_ms
+      .unwrap_or(DEFAULT_IMPERSONATION_EXPIRATION_MS);
+  dbfun::add_impersonation_token(conn, uid, token, admin.id, admin_token, expiredate)?;
+  dbfun::add_audit(
+    conn,
+    Some(admin.id),
+    Some(uid),
+    "impersonate",
+    Some(format!("{} as {}", admin.name, target.name).as_str()),
+  )?;
+  tokener.set(token)?;
+
+  let mut ld = dbfun::login_data(conn, uid)?;
+  ld.data = (callbacks.extra_login_data)(conn, uid)?.map(|x| x.to_string());
+  ld.impersonator = Some(admin.id);
+  Ok(AdminResponse::ArpImpersonating(ld))
+}
+
+// false if on_delete_user declined.
+fn admin_delete_user(
+  conn: &Connection,
+  config: &Config,
+  admin: UserId,
+  callbacks: &mut Callbacks,
+  uid: UserId,
+) -> Result<bool, error::Error> {
+  if config.delete_grace_period_ms.is_some() {
+    // soft delete; purge_deleted_users does the rest after the grace period.
+    // the user's pending invites still work, on behalf of this admin.
+    dbfun::reassign_userinvites(conn, uid, admin)?;
+    dbfun::soft_delete_user(conn, uid)?;
+    return Ok(true);
+  }
+  conn.execute("begin transaction", params!())?;
+  match (callbacks.on_delete_user)(conn, uid).and_then(|ok| {
+    if ok {
+      dbfun::delete_user(conn, uid)?;
+    }
+    Ok(ok)
+  }) {
+    Ok(true) => {
+      conn.execute("commit", params!())?;
+      Ok(true)
+    }
+    Ok(false) => {
+      conn.execute("rollback", params!())?;
+      Ok(false)
+    }
+    Err(e) => {
+      conn.execute("rollback", params!())?;
+      Err(e)
+    }
+  }
+}
+
+// make the account and its password.  Doesn't check the name or email.
+fn admin_create_user(
+  conn: &Connection,
+  config: &Config,
+  admin: UserId,
+  callbacks: &mut Callbacks,
+  cu: &CreateUser,
+) -> Result<CreatedUser, error::Error> {
+  let temp_password = match cu.password {
+    NewUserPassword::NupTemporary => Some(util::temp_password(12)),
+    NewUserPassword::NupEmailLink => None,
+  };
+
+  // a savepoint, so imports can nest this in their own.
+  conn.execute("savepoint create_user", params!())?;
+  let created = dbfun::new_user(
+    conn,
+    &RegistrationData {
+      uid: cu.name.clone(),
+      // with an email link, unusable until the user sets their own.
+      pwd: temp_password
+        .clone()
+        .unwrap_or_else(|| util::get_rand_string(32)),
+      email: cu.email.clone(),
+      remote_url: "".to_string(),
+    },
+    None,
+    cu.data.clone(),
+    cu.admin,
+    None,
+    Some(admin),
+    None,
+    None,
+    None,
+    &mut callbacks.on_new_user,
+  )
+  .and_then(|uid| {
+    if cu.require_password_change {
+      dbfun::set_must_change_password(conn, uid, true)?;
+    }
+    let reset_key = match cu.password {
+      NewUserPassword::NupEmailLink => {
+        let reset_key = Uuid::new_v4();
+        dbfun::add_newpassword(conn, uid, reset_key)?;
+        Some(reset_key)
+      }
+      NewUserPassword::NupTemporary => None,
+    };
+    dbfun::add_audit(conn, Some(admin), Some(uid), "create user", None)?;
+    Ok((uid, reset_key))
+  });
+  let (uid, reset_key) = match created {
+    Ok(x) => {
+      conn.execute("release create_user", params!())?;
+      x
+    }
+    Err(e) => {
+      conn.execute("rollback to create_user", params!())?;
+      conn.execute("release create_user", params!())?;
+      return Err(e);
+    }
+  };
+
+  let ld = dbfun::login_data(conn, uid)?;
+  let reset_url = match reset_key {
+    Some(reset_key) => {
+      if config.send_emails {
+        email::send_reset(
+          config.appname.as_str(),
+          config.emaildomain.as_str(),
+          config.mainsite.as_str(),
+          ld.email.as_str(),
+          ld.name.as_str(),
+          reset_key.to_string().as_str(),
+        )?;
+      }
+      Some(format!(
+        "{}/reset/{}/{}",
+        config.mainsite, ld.name, reset_key
+      ))
+    }
+    None => None,
+  };
+
+  Ok(CreatedUser {
+    login_data: ld,
+    temp_password,
+    reset_url,
+  })
+}
+
+fn import_users(
+  conn: &Connection,
+  config: &Config,
+  admin: UserId,
+  callbacks: &mut Callbacks,
+  iu: &ImportUsers,
+) -> Result<Vec<ImportRow>, error::Error> {
+  let rows = match &iu.source {
+    ImportSource::IsCsv(text) => util::parse_import_csv(text.as_str()),
+    ImportSource::IsJson(text) => serde_json::from_str::<Vec<ImportUser>>(text.as_str())?
+      .into_iter()
+      .enumerate()
+      .map(|(i, u)| ((i + 1) as i64, Ok(u)))
+      .collect(),
+  };
+
+  let mut seen = ImportSeen::default();
+  let mut results = Vec::new();
+  for (row, r) in rows {
+    let (name, outcome) = match r {
+      Ok(iuser) => {
+        // each row is all or nothing; a failed row leaves nothing behind.
+        conn.execute("savepoint import_row", params!())?;
+        let outcome = match import_user(conn, config, admin, callbacks, iu, &iuser, &mut seen) {
+          Ok(outcome) => {
+            conn.execute("release import_row", params!())?;
+            outcome
+          }
+          Err(e) => {
+            conn.execute("rollback to import_row", params!())?;
+            conn.execute("release import_row", params!())?;
+            ImportOutcome::IoError(e.to_string())
+          }
+        };
+        (iuser.name.clone(), outcome)
+      }
+      Err(e) => (String::new(), ImportOutcome::IoError(e)),
+    };
+    results.push(ImportRow { row, name, outcome });
+  }
+  Ok(results)
+}
+
+// names and emails from earlier rows in an import.
+#[derive(Default)]
+struct ImportSeen {
+  names: HashSet<String>,
+  emails: HashSet<String>,
+}
+
+fn import_user(
+  conn: &Connection,
+  config: &Config,
+  admin: UserId,
+  callbacks: &mut Callbacks,
+  iu: &ImportUsers,
+  iuser: &ImportUser,
+  seen: &mut ImportSeen,
+) -> Result<ImportOutcome, error::Error> {
+  let email = match util::normalize_email(iuser.email.as_str()) {
+    Some(e) => e,
+    None => return Ok(ImportOutcome::IoError("invalid email".to_string())),
+  };
+  if seen.emails.contains(&email) || dbfun::email_in_use(conn, email.as_str(), None)? {
+    return Ok(ImportOutcome::IoEmailInUse);
+  }
+  match check_email_domain(config, iuser.email.as_str())? {
+    Some(UserResponse::UrpEmailDomainBlocked) => {
+      return Ok(ImportOutcome::IoError(
+        "email domain is blocked".to_string(),
+      ))
+    }
+    Some(_) => {
+      return Ok(ImportOutcome::IoError(
+        "email domain is not allowed".to_string(),
+      ))
+    }
+    None => (),
+  }
+
+  match iu.mode {
+    ImportMode::ImCreate => {
+      let name = username::normalize(iuser.name.as_str());
+      if seen.names.contains(&name) {
+        return Ok(ImportOutcome::IoInvalidUserName(UsernameError::UneTaken));
+      }
+      if let Err(e) = dbfun::check_username(conn, config, iuser.name.as_str(), None)? {
+        return Ok(ImportOutcome::IoInvalidUserName(e));
+      }
+      seen.names.insert(name);
+      seen.emails.insert(email);
+      if iu.dry_run {
+        return Ok(ImportOutcome::IoValid);
+      }
+
+      let created = admin_create_user(
+        conn,
+        config,
+        admin,
+        callbacks,
+        &CreateUser {
+          name: iuser.name.clone(),
+          email: iuser.email.clone(),
+          admin: iuser.admin,
+          data: iuser.data.clone(),
+          password: NewUserPassword::NupEmailLink,
+          require_password_change: false,
+        },
+      )?;
+      Ok(ImportOutcome::IoCreated(created.login_data.userid))
+    }
+    ImportMode::ImInvite => {
+      if iuser.admin {
+        return Ok(ImportOutcome::IoError(
+          "invites can't make admin users".to_string(),
+        ));
+      }
+      seen.emails.insert(email);
+      if iu.dry_run {
+        return Ok(ImportOutcome::IoValid);
+      }
+      let invite = make_invite(
+        conn,
+        config,
+        admin,
+        &GetInvite {
+          email: Some(iuser.email.clone()),
+          data: iuser.data.clone(),
+          org: None,
+          expiration_ms: None,
+          max_uses: None,
+          send_email: false,
+          message: None,
+        },
+      )?;
+      Ok(ImportOutcome::IoInvited(invite))
+    }
+  }
+}
+
+fn make_invite(
+  conn: &Connection,
+  config: &Config,
+  creator: UserId,
+  gi: &GetInvite,
+) -> Result<UserInvite, error::Error> {
+  let max_uses = gi.max_uses.unwrap_or(1);
+  if max_uses < 1 {
+    return Err("invite max_uses must be at least 1".into());
+  }
+  let expiration_ms = gi
+    .expiration_ms
+    .unwrap_or(config.invite_token_expiration_ms);
+  if expiration_ms <= 0 {
+    return Err("invite expiration must be positive".into());
+  }
+
+  let email = gi.email.as_ref().filter(|e| e.trim() != "").cloned();
+  if gi.send_email && email.is_none() {
+    return Err("can't send an invite without an email address".into());
+  }
+
+  let invite_key = Uuid::new_v4();
+  dbfun::add_userinvite(
+    conn,
+    invite_key,
+    email.clone(),
+    creator,
+    gi.data.clone(),
+    gi.org,
+    now()? + expiration_ms,
+    max_uses,
+  )?;
+  let invite = UserInvite {
+    email,
+    token: invite_key.to_string(),
+    url: format!("{}/invite/{}", config.mainsite, invite_key),
+    creator,
+    data: gi.data.clone(),
+    org: gi.org,
+  };
+
+  if gi.send_email && config.send_emails {
+    if let Some(email) = &invite.email {
+      let inviter = dbfun::read_user_by_id(conn, creator)?;
+      email::send_invite(
+        config.appname.as_str(),
+        config.emaildomain.as_str(),
+        email.as_str(),
+        inviter.name.as_str(),
+        invite.url.as_str(),
+        gi.message.as_deref(),
+      )?;
+      dbfun::set_userinvite_emailed(conn, invite.token.as_str())?;
+    }
+  }
+
+  Ok(invite)
+}
+
+// is the domain in the blocklist file?  The file is read again when its mtime
+// changes.  One that can't be read is logged and treated as empty, rather than
+// failing every signup, and read again on the next check.
+fn blocklist_file_matches(path: &Path, domain: &str) -> bool {
+  // each file's mtime and domains.
+  type Blocklists = HashMap<PathBuf, (SystemTime, Vec<String>)>;
+  static FILES: OnceLock<Mutex<Blocklists>> = OnceLock::new();
+  let mut files = FILES
+    .get_or_init(|| Mutex::new(HashMap::new()))
+    .lock()
+    .unwrap_or_else(|e| e.into_inner());
+  let loaded = std::fs::metadata(path)
+    .and_then(|m| m.modified())
+    .map_err(error::Error::from)
+    .and_then(|mtime| match files.get(path) {
+      Some((t, _)) if *t == mtime => Ok(()),
+      _ => {
+        let domains = util::load_string(path.to_string_lossy().as_ref())?
+          .lines()
+          .map(|l| l.trim())
+          .filter(|l| !l.is_empty() && !l.starts_with('#'))
+          .map(|l| l.to_string())
+          .collect();
+        files.insert(path.to_path_buf(), (mtime, domains));
+        Ok(())
+      }
+    });
+  match loaded {
+    Ok(()) => files
+      .get(path)
+      .map(|(_, domains)| {
+        domains
+          .iter()
+          .any(|p| util::domain_matches(p.as_str(), domain))
+      })
+      .unwrap_or(false),
+    Err(e) => {
+      files.remove(path);
+      error!(
+        "can't read email_domain_blocklist_file {}: {}",
+        path.display(),
+        e
+      );
+      false
+    }
+  }
+}
+
+// a rejection response if the email's domain isn't allowed.
+fn check_email_domain(config: &Config, email: &str) -> Result<Option<UserResponse>, error::Error> {
+  let domain = util::email_domain(email).unwrap_or("".to_string());
+  let mut blocked = config
+    .email_domain_blocklist
+    .iter()
+    .any(|p| util::domain_matches(p.as_str(), domain.as_str()));
+  if !blocked {
+    if let Some(f) = &config.email_domain_blocklist_file {
+      blocked = blocklist_file_matches(f.as_path(), domain.as_str());
+    }
+  }
+  if blocked {
+    Ok(Some(UserResponse::UrpEmailDomainBlocked))
+  } else if !config.email_domain_allowlist.is_empty()
+    && !config
+      .email_domain_allowlist
+      .iter()
+      .any(|p| util::domain_matches(p.as_str(), domain.as_str()))
+  {
+    Ok(Some(UserResponse::UrpEmailDomainNotAllowed))
+  } else {
+    Ok(None)
+  }
+}
+
+// invites made out to an email can only be used with that email.
+fn invite_email_ok(invite: &UserInvite, email: &str) -> bool {
+  match invite.email.as_deref().and_then(util::normalize_email) {
+    Some(ie) => util::normalize_email(email) == Some(ie),
+    None => true,
+  }
+}
+
+fn bulk_user_op(
+  conn: &Connection,
+  config: &Config,
+  admin: UserId,
+  callbacks: &mut Callbacks,
+  op: BulkOp,
+  uid: UserId,
+) -> Result<BulkOutcome, error::Error> {
+  let mut user = match dbfun::read_user_by_id(conn, uid) {
+    Ok(user) => user,
+    Err(error::Error::Rusqlite(rusqlite::Error::QueryReturnedNoRows)) => {
+      return Ok(BulkOutcome::BorNotFound)
+    }
+    Err(e) => return Err(e),
+  };
+  if dbfun::user_deleted(conn, uid)? {
+    return Ok(BulkOutcome::BorNotFound);
+  }
+  match op {
+    BulkOp::BopDeactivate | BulkOp::BopDelete if uid == admin => Ok(BulkOutcome::BorError(
+      "can't deactivate or delete yourself".to_string(),
+    )),
+    BulkOp::BopDeactivate | BulkOp::BopActivate => {
+      user.active = matches!(op, BulkOp::BopActivate);
+      dbfun::update_user(conn, &user)?;
+      Ok(BulkOutcome::BorDone)
+    }
+    BulkOp::BopDelete => {
+      if admin_delete_user(conn, config, admin, callbacks, uid)? {
+        Ok(BulkOutcome::BorDone)
+      } else {
+        Ok(BulkOutcome::BorNotDeleted)
+      }
+    }
+    BulkOp::BopLogout => {
+      dbfun::remove_user_tokens(conn, uid)?;
+      Ok(BulkOutcome::BorDone)
+    }
+  }
+}
+
 pub fn admin_interface(
   conn: &Connection,
   config: &Config,
@@ -600,45 +1772,189 @@ pub fn admin_interface(
   callbacks: &mut Callbacks,
   msg: &AdminRequest,
 ) -> Result<AdminResponse, error::Error> {
+  let file_config = config;
+  let config = &dbfun::current_config(conn, file_config)?;
   match msg {
     AdminRequest::ArqGetUsers => {
       let users = dbfun::read_users(&conn, &mut callbacks.extra_login_data)?;
       Ok(AdminResponse::ArpUsers(users))
     }
     AdminRequest::ArqDeleteUser(uid) => {
-      let uid = uid.clone();
-      conn.execute("begin transaction", params!())?;
-      if (callbacks.on_delete_user)(&conn, uid)? {
-        dbfun::delete_user(&conn, uid)?;
-        conn.execute("commit", params!())?;
-        Ok(AdminResponse::ArpUserDeleted(uid))
+      if admin_delete_user(conn, config, user.id, callbacks, *uid)? {
+        Ok(AdminResponse::ArpUserDeleted(*uid))
       } else {
-        conn.execute("rollback", params!())?;
-        Ok(AdminResponse::ArpUserNotDeleted(uid))
+        Ok(AdminResponse::ArpUserNotDeleted(*uid))
       }
     }
     AdminRequest::ArqUpdateUser(ld) => {
+      let current = dbfun::read_user_by_id(conn, ld.userid)?;
+      if username::normalize(ld.name.as_str()) != current.name {
+        if let Err(e) = dbfun::check_username(conn, config, ld.name.as_str(), Some(ld.userid))? {
+          return Ok(AdminResponse::ArpInvalidUserName(e));
+        }
+      }
+      if ld.email != current.email && dbfun::email_in_use(conn, ld.email.as_str(), Some(ld.userid))?
+      {
+        return Ok(AdminResponse::ArpEmailInUse);
+      }
       dbfun::update_login_data(&conn, &ld)?;
       let uld = dbfun::login_data(&conn, ld.userid)?;
       Ok(AdminResponse::ArpUserUpdated(uld))
     }
-    AdminRequest::ArqGetInvite(gi) => {
-      let invite_key = Uuid::new_v4();
-
-      dbfun::add_userinvite(
-        &conn,
-        invite_key.clone(),
-        gi.email.clone(),
-        user.id,
-        gi.data.clone(),
+    AdminRequest::ArqGetInvite(gi) => Ok(AdminResponse::ArpUserInvite(make_invite(
+      conn, config, user.id, gi,
+    )?)),
+    AdminRequest::ArqGetInvites => Ok(AdminResponse::ArpInvites(dbfun::read_userinvites(
+      conn,
+      config.mainsite.as_str(),
+      config.invite_token_expiration_ms,
+      None,
+    )?)),
+    AdminRequest::ArqRevokeInvite(token) => {
+      if dbfun::revoke_userinvite(conn, token.as_str(), None)? {
+        dbfun::add_audit(
+          conn,
+          Some(user.id),
+          None,
+          "revoke invite",
+          Some(token.as_str()),
+        )?;
+        Ok(AdminResponse::ArpInviteRevoked(token.clone()))
+      } else {
+        Err("invite not found".into())
+      }
+    }
+    AdminRequest::ArqGetInviteUses => Ok(AdminResponse::ArpInviteUses(dbfun::read_invite_uses(
+      conn, None,
+    )?)),
+    AdminRequest::ArqGetInviteQuota(uid) => Ok(AdminResponse::ArpInviteQuota(
+      dbfun::read_invite_quota(conn, config, *uid)?,
+    )),
+    AdminRequest::ArqSetInviteQuota(iq) => {
+      if iq.quota.map(|q| q < 0).unwrap_or(false) || iq.period_ms.map(|p| p <= 0).unwrap_or(false) {
+        return Err("invalid invite quota".into());
+      }
+      dbfun::save_invite_quota(conn, iq)?;
+      dbfun::add_audit(
+        conn,
+        Some(user.id),
+        Some(iq.userid),
+        if iq.revoked {
+          "revoke invite privilege"
+        } else {
+          "set invite quota"
+        },
+        None,
       )?;
-      Ok(AdminResponse::ArpUserInvite(UserInvite {
-        email: None,
-        token: invite_key.to_string(),
-        url: format!("{}/invite/{}", config.mainsite, invite_key.to_string()),
-        creator: user.id,
-        data: gi.data.clone(),
-      }))
+      Ok(AdminResponse::ArpInviteQuota(dbfun::read_invite_quota(
+        conn, config, iq.userid,
+      )?))
+    }
+    AdminRequest::ArqGetPendingRegistrations => Ok(AdminResponse::ArpPendingRegistrations(
+      dbfun::read_pending_registrations(conn)?,
+    )),
+    AdminRequest::ArqApproveRegistration(rd) => {
+      if !dbfun::approval_pending(conn, rd.userid)? {
+        return Err("user is not awaiting approval".into());
+      }
+      dbfun::set_approval_pending(conn, rd.userid, false)?;
+      dbfun::add_audit(
+        conn,
+        Some(user.id),
+        Some(rd.userid),
+        "approve registration",
+        rd.message.as_deref(),
+      )?;
+      let applicant = dbfun::read_user_by_id(conn, rd.userid)?;
+      if config.send_emails {
+        email::send_registration_decision(
+          config.appname.as_str(),
+          config.emaildomain.as_str(),
+          config.mainsite.as_str(),
+          applicant.email.as_str(),
+          applicant.name.as_str(),
+          true,
+          rd.message.as_deref(),
+        )?;
+      }
+      Ok(AdminResponse::ArpRegistrationApproved(rd.userid))
+    }
+    AdminRequest::ArqRejectRegistration(rd) => {
+      if !dbfun::approval_pending(conn, rd.userid)? {
+        return Err("user is not awaiting approval".into());
+      }
+      let applicant = dbfun::read_user_by_id(conn, rd.userid)?;
+      dbfun::add_audit(
+        conn,
+        Some(user.id),
+        Some(rd.userid),
+        "reject registration",
+        Some(applicant.name.as_str()),
+      )?;
+      if !admin_delete_user(conn, config, user.id, callbacks, rd.userid)? {
+        return Err("rejected user could not be deleted".into());
+      }
+      if config.send_emails {
+        email::send_registration_decision(
+          config.appname.as_str(),
+          config.emaildomain.as_str(),
+          config.mainsite.as_str(),
+          applicant.email.as_str(),
+          applicant.name.as_str(),
+          false,
+          rd.message.as_deref(),
+        )?;
+      }
+      Ok(AdminResponse::ArpRegistrationRejected(rd.userid))
+    }
+    AdminRequest::ArqGetRegistrationLink(uid) => {
+      let u = dbfun::read_user_by_id(conn, *uid)?;
+      if dbfun::is_phantom(&u) {
+        return Err("phantom users can't register".into());
+      }
+      match u.registration_key {
+        Some(key) => Ok(AdminResponse::ArpRegistrationLink(format!(
+          "{}/register/{}/{}",
+          config.mainsite, u.name, key
+        ))),
+        None => Err("user is already registered".into()),
+      }
+    }
+    AdminRequest::ArqMarkRegistered(uid) => {
+      let mut u = dbfun::read_user_by_id(conn, *uid)?;
+      if dbfun::is_phantom(&u) {
+        return Err("phantom users can't register".into());
+      }
+      if u.registration_key.is_none() {
+        return Err("user is already registered".into());
+      }
+      u.registration_key = None;
+      dbfun::update_user(conn, &u)?;
+      dbfun::add_audit(conn, Some(user.id), Some(*uid), "mark registered", None)?;
+      Ok(AdminResponse::ArpUserRegistered(*uid))
+    }
+    AdminRequest::ArqGetReferrals(root) => Ok(AdminResponse::ArpReferrals(dbfun::read_referrals(
+      conn, *root,
+    )?)),
+    AdminRequest::ArqDisableSubtree(uid) => {
+      conn.execute("begin transaction", params!())?;
+      match dbfun::disable_subtree(conn, config, *uid) {
+        Ok(disabled) => {
+          dbfun::add_audit(
+            conn,
+            Some(user.id),
+            Some(*uid),
+            "disable subtree",
+            Some(format!("{} users", disabled.len()).as_str()),
+          )?;
+          conn.execute("commit", params!())?;
+          Ok(AdminResponse::ArpSubtreeDisabled(disabled))
+        }
+        Err(e) => {
+          conn.execute("rollback", params!())?;
+          Err(e)
+        }
+      }
     }
     AdminRequest::ArqGetPwdReset(uid) => {
       let uid = uid.clone();
@@ -669,6 +1985,255 @@ pub fn admin_interface(
         ),
       }))
     }
+    AdminRequest::ArqGetRoles => Ok(AdminResponse::ArpRoles(dbfun::read_roles(conn)?)),
+    AdminRequest::ArqSaveRole(role) => {
+      dbfun::save_role(conn, role)?;
+      Ok(AdminResponse::ArpRoleSaved(dbfun::read_role(
+        conn,
+        role.name.as_str(),
+      )?))
+    }
+    AdminRequest::ArqDeleteRole(name) => {
+      dbfun::delete_role(conn, name)?;
+      Ok(AdminResponse::ArpRoleDeleted(name.clone()))
+    }
+    AdminRequest::ArqAssignRole(ur) => {
+      dbfun::assign_role(conn, ur)?;
+      Ok(AdminResponse::ArpRoleAssigned(ur.clone()))
+    }
+    AdminRequest::ArqRevokeRole(ur) => {
+      dbfun::revoke_role(conn, ur)?;
+      Ok(AdminResponse::ArpRoleRevoked(ur.clone()))
+    }
+    AdminRequest::ArqGetOrgs => Ok(AdminResponse::ArpOrgs(dbfun::read_orgs(conn)?)),
+    AdminRequest::ArqGetProfile(uid) => {
+      Ok(AdminResponse::ArpProfile(dbfun::read_profile(conn, *uid)?))
+    }
+    AdminRequest::ArqUpdateProfile(profile) => {
+      let errors = dbfun::validate_profile(&config.profile_fields, profile);
+      if errors.is_empty() {
+        dbfun::save_profile(conn, profile)?;
+        Ok(AdminResponse::ArpProfile(dbfun::read_profile(
+          conn,
+          profile.userid,
+        )?))
+      } else {
+        Ok(AdminResponse::ArpInvalidProfile(errors))
+      }
+    }
+    AdminRequest::ArqGetDeletedUsers => Ok(AdminResponse::ArpDeletedUsers(
+      dbfun::read_deleted_users(conn)?,
+    )),
+    AdminRequest::ArqRestoreUser(uid) => {
+      dbfun::restore_user(conn, *uid)?;
+      Ok(AdminResponse::ArpUserRestored(dbfun::login_data(
+        conn, *uid,
+      )?))
+    }
+    AdminRequest::ArqExportUser(uid) => Ok(AdminResponse::ArpUserExport(export_user_data(
+      conn, callbacks, *uid,
+    )?)),
+    AdminRequest::ArqSuspendUser(su) => {
+      if su.userid == user.id {
+        return Err("can't suspend yourself".into());
+      }
+      Ok(AdminResponse::ArpUserSuspended(dbfun::suspend_user(
+        conn, user.id, su,
+      )?))
+    }
+    AdminRequest::ArqLiftSuspension(uid) => {
+      dbfun::lift_suspension(conn, *uid)?;
+      Ok(AdminResponse::ArpSuspensionLifted(*uid))
+    }
+    AdminRequest::ArqGetSuspensions => Ok(AdminResponse::ArpSuspensions(dbfun::read_suspensions(
+      conn,
+    )?)),
+    AdminRequest::ArqQueryUsers(query) => {
+      Ok(AdminResponse::ArpUserPage(dbfun::query_users(conn, query)?))
+    }
+    AdminRequest::ArqImportUsers(iu) => Ok(AdminResponse::ArpImportResult(import_users(
+      conn, config, user.id, callbacks, iu,
+    )?)),
+    AdminRequest::ArqBulkUserOp(bop) => Ok(AdminResponse::ArpBulkResult(
+      bop
+        .users
+        .iter()
+        .map(|uid| BulkResult {
+          userid: *uid,
+          outcome: bulk_user_op(conn, config, user.id, callbacks, bop.op, *uid)
+            .unwrap_or_else(|e| BulkOutcome::BorError(e.to_string())),
+        })
+        .collect(),
+    )),
+    // handled in admin_interface_check, which has the session.
+    AdminRequest::ArqImpersonate(_) => Err("impersonation requires a login session".into()),
+    AdminRequest::ArqGetAuditLog(query) => Ok(AdminResponse::ArpAuditLog(dbfun::read_audit_log(
+      conn, query,
+    )?)),
+    AdminRequest::ArqCreateUser(cu) => {
+      if let Err(e) = dbfun::check_username(conn, config, cu.name.as_str(), None)? {
+        return Ok(AdminResponse::ArpInvalidUserName(e));
+      }
+      if util::normalize_email(cu.email.as_str()).is_none() {
+        return Err("invalid email".into());
+      }
+      if dbfun::email_in_use(conn, cu.email.as_str(), None)? {
+        return Ok(AdminResponse::ArpEmailInUse);
+      }
+      Ok(AdminResponse::ArpUserCreated(admin_create_user(
+        conn, config, user.id, callbacks, cu,
+      )?))
+    }
+    AdminRequest::ArqGetSettings => Ok(AdminResponse::ArpSettings(admin_settings(config))),
+    AdminRequest::ArqUpdateSettings(settings) => {
+      if let Err(e) = dbfun::validate_settings(settings) {
+        return Ok(AdminResponse::ArpInvalidSettings(e));
+      }
+      dbfun::save_settings(conn, settings)?;
+      dbfun::add_audit(
+        conn,
+        Some(user.id),
+        None,
+        "update settings",
+        Some(serde_json::to_string(settings)?.as_str()),
+      )?;
+      Ok(AdminResponse::ArpSettings(admin_settings(
+        &dbfun::current_config(conn, config)?,
+      )))
+    }
+    AdminRequest::ArqResetSettings => {
+      dbfun::clear_settings(conn)?;
+      dbfun::add_audit(conn, Some(user.id), None, "reset settings", None)?;
+      Ok(AdminResponse::ArpSettings(admin_settings(file_config)))
+    }
+    AdminRequest::ArqGroupRequest(greq) => Ok(AdminResponse::ArpGroupResponse(group_interface(
+      conn, user.id, true, greq,
+    )?)),
+  }
+}
+
+// hard delete users whose grace period is over.  Call periodically, like dbfun::purge_tokens.
+pub fn purge_deleted_users(config: &Config, callbacks: &mut Callbacks) -> Result<(), error::Error> {
+  if let Some(grace_period_ms) = config.delete_grace_period_ms {
+    let conn = dbfun::connection_open(config.db.as_path())?;
+    dbfun::purge_deleted_users(&conn, grace_period_ms, &mut callbacks.on_delete_user)?;
+  }
+  Ok(())
+}
+
+// cancel other change requests, and send the old address a link to undo the change.
+fn email_changed(
+  conn: &Connection,
+  config: &Config,
+  user: &User,
+  new_email: &str,
+) -> Result<(), error::Error> {
+  // the confirmation link went to the new address.
+  dbfun::set_email_verified(conn, user.id)?;
+  dbfun::cancel_newemails(conn, user.id, None)?;
+  let token = Uuid::new_v4();
+  dbfun::add_email_revert(conn, user.id, user.email.as_str(), new_email, token)?;
+  if config.send_emails {
+    email::send_email_changed_notice(
+      config.appname.as_str(),
+      config.emaildomain.as_str(),
+      config.mainsite.as_str(),
+      user,
+      new_email,
+      token.to_string().as_str(),
+      now()?
+        + config
+          .email_revert_expiration_ms
+          .unwrap_or(DEFAULT_EMAIL_REVERT_EXPIRATION_MS),
+    )?;
+  }
+  Ok(())
+}
+
+pub fn revert_email(data: &Config, req: HttpRequest) -> HttpResponse {
+  info!("revert email: uid: {:?}", req.match_info().get("uid"));
+  let conn = match dbfun::connection_open(data.db.as_path()) {
+    Ok(c) => c,
+    Err(_e) => {
+      return HttpResponse::InternalServerError()
+        .body("<h1>database connection failed</h1>".to_string())
+    }
+  };
+  let (uid, token) = match (req.match_info().get("uid"), req.match_info().get("token")) {
+    (Some(uid), Some(tokenstr)) => match Uuid::from_str(tokenstr) {
+      Ok(token) => (uid, token),
+      Err(_e) => return HttpResponse::BadRequest().body("invalid token".to_string()),
+    },
+    _ => return HttpResponse::BadRequest().body("username or token not found!".to_string()),
+  };
+  let revert_expiration_ms = match dbfun::current_config(&conn, data) {
+    Ok(c) => c.email_revert_expiration_ms,
+    Err(e) => {
+      error!("error reading settings: {:?}", e);
+      data.email_revert_expiration_ms
+    }
+  }
+  .unwrap_or(DEFAULT_EMAIL_REVERT_EXPIRATION_MS);
+  match dbfun::resolve_user_by_name(&conn, uid)
+    .and_then(|user| dbfun::revert_email(&conn, user.id, token, revert_expiration_ms))
+  {
+    Ok(true) => HttpResponse::Ok().body(
+      format!(
+        "<h1>Email address restored!<h1> All sessions have been logged out.  \
+         Reset your password, then <a href=\"{}\">proceed to the main site</a>",
+        data.mainsite
+      )
+      .to_string(),
+    ),
+    Ok(false) => HttpResponse::UnprocessableEntity()
+      .body("<h1>email revert failed - link expired or already used</h1>".to_string()),
+    Err(e) => {
+      error!("error reverting email: {:?}", e);
+      HttpResponse::InternalServerError().body("<h1>email revert failed</h1>".to_string())
+    }
+  }
+}
+
+pub fn verify_email(data: &Config, req: HttpRequest) -> HttpResponse {
+  info!("verify email: uid: {:?}", req.match_info().get("uid"));
+  let conn = match dbfun::connection_open(data.db.as_path()) {
+    Ok(c) => c,
+    Err(_e) => {
+      return HttpResponse::InternalServerError()
+        .body("<h1>database connection failed</h1>".to_string())
+    }
+  };
+  let (uid, token) = match (req.match_info().get("uid"), req.match_info().get("token")) {
+    (Some(uid), Some(tokenstr)) => match Uuid::from_str(tokenstr) {
+      Ok(token) => (uid, token),
+      Err(_e) => return HttpResponse::BadRequest().body("invalid token".to_string()),
+    },
+    _ => return HttpResponse::BadRequest().body("username or token not found!".to_string()),
+  };
+  let email_token_expiration_ms = match dbfun::current_config(&conn, data) {
+    Ok(c) => c.email_token_expiration_ms,
+    Err(e) => {
+      error!("error reading settings: {:?}", e);
+      data.email_token_expiration_ms
+    }
+  };
+  match dbfun::resolve_user_by_name(&conn, uid)
+    .and_then(|user| dbfun::verify_email(&conn, user.id, token, email_token_expiration_ms))
+  {
+    Ok(true) => HttpResponse::Ok().body(
+      format!(
+        "<h1>Email verified!<h1> <a href=\"{}\">\
+         Proceed to the main site</a>",
+        data.mainsite
+      )
+      .to_string(),
+    ),
+    Ok(false) => HttpResponse::UnprocessableEntity()
+      .body("<h1>email verification failed - link expired or already used</h1>".to_string()),
+    Err(e) => {
+      error!("error verifying email: {:?}", e);
+      HttpResponse::InternalServerError().body("<h1>email verification failed</h1>".to_string())
+    }
   }
 }
 
@@ -678,13 +2243,21 @@ pub fn register(data: &Config, req: HttpRequest) -> HttpResponse {
     Ok(conn) => match (req.match_info().get("uid"), req.match_info().get("key")) {
       (Some(uid), Some(key)) => {
         // read user record.  does the reg key match?
-        match dbfun::read_user_by_name(&conn, uid) {
+        match dbfun::resolve_user_by_name(&conn, uid) {
           Ok(user) => {
             if user.registration_key == Some(key.to_string()) {
               let mut mu = user;
               mu.registration_key = None;
-              match dbfun::update_user(&conn, &mu) {
-                Ok(_) => HttpResponse::Ok().body(
+              match dbfun::update_user(&conn, &mu)
+                .and_then(|_| dbfun::set_email_verified(&conn, mu.id))
+                .and_then(|_| dbfun::approval_pending(&conn, mu.id))
+              {
+                Ok(true) => HttpResponse::Ok().body(
+                  "<h1>Your email is confirmed!<h1> \
+                   Your registration is waiting for approval by the site admins."
+                    .to_string(),
+                ),
+                Ok(false) => HttpResponse::Ok().body(
                   format!(
                     "<h1>You are registered!<h1> <a href=\"{}\">\
                        Proceed to the main site</a>",
@@ -717,7 +2290,7 @@ pub fn new_email(data: &Config, req: HttpRequest) -> HttpResponse {
           Err(_e) => HttpResponse::BadRequest().body("invalid token".to_string()),
           Ok(token) => {
             // read user record.  does the reg key match?
-            match dbfun::read_user_by_name(&conn, uid) {
+            match dbfun::resolve_user_by_name(&conn, uid) {
               Ok(user) => {
                 match dbfun::read_newemail(&conn, user.id, token) {
                   Ok((email, tokendate)) => {
@@ -726,7 +2299,14 @@ pub fn new_email(data: &Config, req: HttpRequest) -> HttpResponse {
                         .body("<h1>'now' failed!</h1>".to_string()),
 
                       Ok(now) => {
-                        if (now - tokendate) > data.email_token_expiration_ms {
+                        let config = match dbfun::current_config(&conn, data) {
+                          Ok(c) => c,
+                          Err(e) => {
+                            error!("error reading settings: {:?}", e);
+                            data.clone()
+                          }
+                        };
+                        if (now - tokendate) > config.email_token_expiration_ms {
                           // TODO token expired?
                           HttpResponse::UnprocessableEntity()
                             .body("<h1>email change failed - token expired</h1>".to_string())
@@ -741,6 +2321,10 @@ pub fn new_email(data: &Config, req: HttpRequest) -> HttpResponse {
                                 Ok(_) => (),
                                 Err(e) => error!("error removing newemail record: {:?}", e),
                               }
+                              match email_changed(&conn, &config, &user, mu.email.as_str()) {
+                                Ok(_) => (),
+                                Err(e) => error!("error notifying old email address: {:?}", e),
+                              }
                               HttpResponse::Ok().body(
                                 format!(
                                   "<h1>Email address changed!<h1> <a href=\"{}\">\
@@ -775,3 +2359,642 @@ pub fn new_email(data: &Config, req: HttpRequest) -> HttpResponse {
     }
   }
 }
+
+#[cfg(test)]
+pub mod tests {
+  use super::*;
+  use crate::data::{
+    DeleteAccount, GetInvite, GroupUser, InviteQuota, OrgUser, SetOrgMemberRole, Subgroup, RSVP,
+  };
+  use crate::migrations::tests::test_db;
+
+  fn test_config(db: &Path) -> Config {
+    serde_json::from_value(serde_json::json!({
+      "mainsite": "https://example.com",
+      "appname": "test",
+      "emaildomain": "example.com",
+      "db": db,
+      "admin_email": "admin@example.com",
+      "regen_login_tokens": false,
+      "login_token_expiration_ms": null,
+      "email_token_expiration_ms": 100000,
+      "reset_token_expiration_ms": 100000,
+      "invite_token_expiration_ms": 100000,
+      "open_registration": true,
+      "send_emails": false,
+      "non_admin_invite": true,
+      "remote_registration": false,
+    }))
+    .unwrap()
+  }
+
+  pub fn test_callbacks() -> Callbacks {
+    Callbacks {
+      on_new_user: Box::new(|_, _, _, _, _, _| Ok(())),
+      extra_login_data: Box::new(|_, _| Ok(None)),
+      on_delete_user: Box::new(|_, _| Ok(true)),
+      export_user_data: Box::new(|_, _| Ok(None)),
+    }
+  }
+
+  pub fn test_user(conn: &Connection, name: &str, admin: bool) -> UserId {
+    let rd = RegistrationData {
+      uid: name.to_string(),
+      pwd: "pwd".to_string(),
+      email: format!("{}@example.com", name),
+      remote_url: "".to_string(),
+    };
+    let mut on_new_user = test_callbacks().on_new_user;
+    dbfun::new_user(
+      conn,
+      &rd,
+      None,
+      None,
+      admin,
+      None,
+      None,
+      None,
+      None,
+      None,
+      &mut on_new_user,
+    )
+    .unwrap()
+  }
+
+  fn authed(
+    config: &Config,
+    conn: &Connection,
+    callbacks: &mut Callbacks,
+    uid: UserId,
+    msg: AuthedRequest,
+  ) -> Result<UserResponse, error::Error> {
+    tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(user_interface_loggedin(
+        config, conn, callbacks, uid, None, &msg,
+      ))
+  }
+
+  #[test]
+  fn test_add_subgroup_owner() {
+    let (conn, _) = test_db("test_add_subgroup_owner");
+    let alice = test_user(&conn, "alice", false);
+    let bob = test_user(&conn, "bob", false);
+    let parent = dbfun::new_group(&conn, "parent", alice).unwrap();
+    let mine = dbfun::new_group(&conn, "mine", alice).unwrap();
+    let theirs = dbfun::new_group(&conn, "theirs", bob).unwrap();
+    let add = |subgroup| {
+      GroupRequest::GrqAddSubgroup(Subgroup {
+        group: parent,
+        subgroup,
+      })
+    };
+    assert!(matches!(
+      group_interface(&conn, alice, false, &add(theirs)),
+      Ok(GroupResponse::GrpAccessDenied)
+    ));
+    assert!(matches!(
+      group_interface(&conn, alice, false, &add(mine)),
+      Ok(GroupResponse::GrpGroup(_))
+    ));
+  }
+
+  #[test]
+  fn test_group_membership() {
+    let (conn, _) = test_db("test_group_membership");
+    let admin = test_user(&conn, "admin", true);
+    let alice = test_user(&conn, "alice", false);
+    let bob = test_user(&conn, "bob", false);
+    let carol = test_user(&conn, "carol", false);
+    let parent = dbfun::new_group(&conn, "parent", alice).unwrap();
+    let child = dbfun::new_group(&conn, "child", alice).unwrap();
+    let member = |group, userid| GroupUser { group, userid };
+
+    // only the owner, or an admin, changes the members.
+    let r = group_interface(
+      &conn,
+      bob,
+      false,
+      &GroupRequest::GrqAddMember(member(parent, bob)),
+    );
+    assert!(matches!(r, Ok(GroupResponse::GrpAccessDenied)));
+    let r = group_interface(
+      &conn,
+      alice,
+      false,
+      &GroupRequest::GrqAddMember(member(child, carol)),
+    );
+    assert!(matches!(r, Ok(GroupResponse::GrpGroup(_))));
+    let r = group_interface(
+      &conn,
+      bob,
+      false,
+      &GroupRequest::GrqRemoveMember(member(child, carol)),
+    );
+    assert!(matches!(r, Ok(GroupResponse::GrpAccessDenied)));
+    let r = group_interface(
+      &conn,
+      admin,
+      true,
+      &GroupRequest::GrqAddMember(member(parent, bob)),
+    );
+    assert!(matches!(r, Ok(GroupResponse::GrpGroup(_))));
+
+    // members of a subgroup are effectively members of the parent.
+    let sg = Subgroup {
+      group: parent,
+      subgroup: child,
+    };
+    let r = group_interface(&conn, alice, false, &GroupRequest::GrqAddSubgroup(sg));
+    assert!(matches!(r, Ok(GroupResponse::GrpGroup(_))));
+    let names = |uid| {
+      dbfun::read_user_groups(&conn, uid)
+        .unwrap()
+        .into_iter()
+        .map(|g| g.name)
+        .collect::<Vec<String>>()
+    };
+    assert_eq!(names(carol), vec!["child", "parent"]);
+
+    // deleting a user takes them out of their groups.
+    dbfun::delete_user(&conn, carol).unwrap();
+    let r = dbfun::read_group_info(&conn, child).unwrap();
+    assert!(r.members.is_empty());
+  }
+
+  #[test]
+  fn test_invite_quota() {
+    let (conn, path) = test_db("test_invite_quota");
+    let mut config = test_config(path.as_path());
+    config.invite_quota = Some(3);
+    let mut callbacks = test_callbacks();
+    let admin = test_user(&conn, "admin", true);
+    let user = test_user(&conn, "user", false);
+    let invite = |max_uses, org| {
+      AuthedRequest::AthGetInvite(GetInvite {
+        email: None,
+        data: None,
+        org,
+        expiration_ms: None,
+        max_uses,
+        send_email: false,
+        message: None,
+      })
+    };
+    let mut get_invite =
+      |uid, max_uses| authed(&config, &conn, &mut callbacks, uid, invite(max_uses, None));
+
+    // each use of a multi-use invite counts against the quota.
+    assert!(matches!(
+      get_invite(user, Some(2)),
+      Ok(UserResponse::UrpInvite(_))
+    ));
+    assert!(matches!(
+      get_invite(user, Some(2)),
+      Ok(UserResponse::UrpInviteQuotaExceeded)
+    ));
+    assert!(matches!(
+      get_invite(user, None),
+      Ok(UserResponse::UrpInvite(_))
+    ));
+    assert!(matches!(
+      get_invite(user, None),
+      Ok(UserResponse::UrpInviteQuotaExceeded)
+    ));
+    // admins have no quota.
+    for _ in 0..4 {
+      assert!(matches!(
+        get_invite(admin, None),
+        Ok(UserResponse::UrpInvite(_))
+      ));
+    }
+
+    // revoking the privilege removes the user's invites too.
+    dbfun::save_invite_quota(
+      &conn,
+      &InviteQuota {
+        userid: user,
+        quota: Some(10),
+        period_ms: None,
+        revoked: true,
+      },
+    )
+    .unwrap();
+    assert!(matches!(
+      get_invite(user, None),
+      Ok(UserResponse::UrpInvitesRevoked)
+    ));
+    let outstanding: i64 = conn
+      .query_row(
+        "select count(*) from orgauth_user_invite where creator = ?1",
+        params![user.to_i64()],
+        |row| row.get(0),
+      )
+      .unwrap();
+    assert_eq!(outstanding, 0);
+
+    // org invites need an org owner or admin.
+    let org = dbfun::new_org(&conn, "org", admin).unwrap();
+    dbfun::add_org_member(&conn, org, user, OrgRole::OrMember).unwrap();
+    let r = authed(
+      &config,
+      &conn,
+      &mut callbacks,
+      user,
+      invite(None, Some(org)),
+    );
+    assert!(matches!(r, Ok(UserResponse::UrpOrgAccessDenied)));
+  }
+
+  fn user_request(
+    config: &Config,
+    conn: &Connection,
+    callbacks: &mut Callbacks,
+    msg: UserRequest,
+  ) -> Result<UserResponse, error::Error> {
+    let mut tokener = UuidTokener { uuid: None };
+    tokio::runtime::Runtime::new()
+      .unwrap()
+      .block_on(user_interface(
+        conn,
+        &mut tokener,
+        config,
+        callbacks,
+        None,
+        msg,
+      ))
+  }
+
+  #[test]
+  fn test_rsvp_approval() {
+    let (conn, path) = test_db("test_rsvp_approval");
+    let mut config = test_config(path.as_path());
+    config.registration_approval = true;
+    let mut callbacks = test_callbacks();
+    let admin = test_user(&conn, "admin", true);
+    let friend = test_user(&conn, "friend", false);
+    let pending = test_user(&conn, "pending", false);
+    dbfun::set_approval_pending(&conn, pending, true).unwrap();
+
+    let rsvp = |creator, uid: &str| {
+      let token = Uuid::new_v4();
+      dbfun::add_userinvite(
+        &conn,
+        token,
+        None,
+        creator,
+        None,
+        None,
+        now().unwrap() + 100000,
+        1,
+      )
+      .unwrap();
+      UserRequest::UrqRSVP(RSVP {
+        uid: uid.to_string(),
+        pwd: "pwd".to_string(),
+        email: format!("{}@example.com", uid),
+        invite: token.to_string(),
+      })
+    };
+
+    // a non-admin's invite doesn't approve anyone.
+    let r = user_request(&config, &conn, &mut callbacks, rsvp(friend, "pending"));
+    assert!(matches!(r, Ok(UserResponse::UrpRegistrationPending)));
+    assert!(dbfun::approval_pending(&conn, pending).unwrap());
+    let r = user_request(&config, &conn, &mut callbacks, rsvp(friend, "newbie"));
+    assert!(matches!(r, Ok(UserResponse::UrpRegistrationPending)));
+
+    // an admin's does.
+    let r = user_request(&config, &conn, &mut callbacks, rsvp(admin, "pending"));
+    assert!(matches!(r, Ok(UserResponse::UrpLoggedIn(_))));
+    assert!(!dbfun::approval_pending(&conn, pending).unwrap());
+  }
+
+  #[test]
+  fn test_org_permissions() {
+    let (conn, _) = test_db("test_org_permissions");
+    let owner = test_user(&conn, "owner", false);
+    let admin1 = test_user(&conn, "admin1", false);
+    let admin2 = test_user(&conn, "admin2", false);
+    let member = test_user(&conn, "member", false);
+    let org = dbfun::new_org(&conn, "org", owner).unwrap();
+    dbfun::add_org_member(&conn, org, admin1, OrgRole::OrAdmin).unwrap();
+    dbfun::add_org_member(&conn, org, admin2, OrgRole::OrAdmin).unwrap();
+    dbfun::add_org_member(&conn, org, member, OrgRole::OrMember).unwrap();
+    let as_user = |uid, msg| {
+      let user = dbfun::read_user_by_id(&conn, uid).unwrap();
+      org_interface(&conn, &user, &msg)
+    };
+    let set_role =
+      |userid, role| OrgRequest::OrqSetMemberRole(SetOrgMemberRole { org, userid, role });
+    let remove = |userid| OrgRequest::OrqRemoveMember(OrgUser { org, userid });
+
+    // admins can't demote or remove each other.
+    let r = as_user(admin1, set_role(admin2, OrgRole::OrMember));
+    assert!(matches!(r, Ok(UserResponse::UrpOrgAccessDenied)));
+    let r = as_user(admin1, remove(admin2));
+    assert!(matches!(r, Ok(UserResponse::UrpOrgAccessDenied)));
+    let r = as_user(member, set_role(member, OrgRole::OrAdmin));
+    assert!(matches!(r, Ok(UserResponse::UrpOrgAccessDenied)));
+    assert_eq!(
+      dbfun::read_org_role(&conn, org, admin2).unwrap(),
+      Some(OrgRole::OrAdmin)
+    );
+
+    // they can manage plain members, and the owner can manage them.
+    let r = as_user(admin1, set_role(member, OrgRole::OrAdmin));
+    assert!(matches!(r, Ok(UserResponse::UrpOrgMembers(_))));
+    let r = as_user(owner, set_role(admin2, OrgRole::OrMember));
+    assert!(matches!(r, Ok(UserResponse::UrpOrgMembers(_))));
+    let r = as_user(admin1, remove(admin2));
+    assert!(matches!(r, Ok(UserResponse::UrpOrgMemberRemoved(_))));
+    assert_eq!(dbfun::read_org_role(&conn, org, admin2).unwrap(), None);
+
+    // deleting the owner hands the org to the longest standing admin.
+    dbfun::delete_user(&conn, owner).unwrap();
+    assert_eq!(
+      dbfun::read_org_role(&conn, org, admin1).unwrap(),
+      Some(OrgRole::OrOwner)
+    );
+    assert_eq!(
+      dbfun::read_org_role(&conn, org, member).unwrap(),
+      Some(OrgRole::OrAdmin)
+    );
+
+    // an org with no one else in it goes with its owner.
+    let solo = dbfun::new_org(&conn, "solo", admin2).unwrap();
+    dbfun::delete_user(&conn, admin2).unwrap();
+    assert!(dbfun::read_org(&conn, solo).is_err());
+  }
+
+  #[test]
+  fn test_impersonation_session() {
+    let (conn, path) = test_db("test_impersonation_session");
+    let config = test_config(path.as_path());
+    let mut callbacks = test_callbacks();
+    let admin = test_user(&conn, "admin", true);
+    let user = test_user(&conn, "user", false);
+    let mut request = |tokener: &mut UuidTokener, msg| {
+      tokio::runtime::Runtime::new()
+        .unwrap()
+        .block_on(user_interface(
+          &conn,
+          tokener,
+          &config,
+          &mut callbacks,
+          None,
+          msg,
+        ))
+    };
+
+    let admin_token = Uuid::new_v4();
+    dbfun::add_token(&conn, admin, admin_token, None).unwrap();
+    let mut admin_tokener = UuidTokener {
+      uuid: Some(admin_token),
+    };
+    match admin_interface_check(
+      &mut admin_tokener,
+      &config,
+      &mut test_callbacks(),
+      AdminRequest::ArqImpersonate(user),
+    ) {
+      Ok(AdminResponse::ArpImpersonating(ld)) => assert_eq!(ld.impersonator, Some(admin)),
+      r => panic!("{:?}", r),
+    }
+
+    // the user's own session isn't marked, or limited, by the admin's.
+    let mut user_tokener = UuidTokener { uuid: None };
+    match request(
+      &mut user_tokener,
+      UserRequest::UrqLogin(Login {
+        uid: "user".to_string(),
+        pwd: "pwd".to_string(),
+      }),
+    ) {
+      Ok(UserResponse::UrpLoggedIn(ld)) => assert_eq!(ld.impersonator, None),
+      r => panic!("{:?}", r),
+    }
+    assert_eq!(dbfun::login_data(&conn, user).unwrap().impersonator, None);
+    let change_password = |newpwd: &str| {
+      UserRequest::UrqAuthedRequest(AuthedRequest::AthChangePassword(ChangePassword {
+        oldpwd: "pwd".to_string(),
+        newpwd: newpwd.to_string(),
+      }))
+    };
+    assert!(matches!(
+      request(&mut user_tokener, change_password("pwd")),
+      Ok(UserResponse::UrpChangedPassword)
+    ));
+    // the admin's session is.
+    assert!(matches!(
+      request(&mut admin_tokener, change_password("pwd2")),
+      Ok(UserResponse::UrpImpersonationDenied)
+    ));
+  }
+
+  #[test]
+  fn test_restricted_verification() {
+    let (conn, path) = test_db("test_restricted_verification");
+    let mut config = test_config(path.as_path());
+    config.send_emails = true;
+    config.email_verification = Some(EmailVerification::Restricted);
+    let mut callbacks = test_callbacks();
+
+    // there's no mail server here, but the registration still goes through.
+    let r = user_request(
+      &config,
+      &conn,
+      &mut callbacks,
+      UserRequest::UrqRegister(RegistrationData {
+        uid: "newbie".to_string(),
+        pwd: "pwd".to_string(),
+        email: "newbie@example.com".to_string(),
+        remote_url: "".to_string(),
+      }),
+    );
+    assert!(matches!(r, Ok(UserResponse::UrpLoggedIn(_))));
+    let newbie = dbfun::read_user_by_name(&conn, "newbie").unwrap();
+    assert_eq!(newbie.registration_key, None);
+
+    // unverified users can't create orgs or groups until they verify.
+    assert!(dbfun::email_restricted(&conn, &config, newbie.id).unwrap());
+    let r = authed(
+      &config,
+      &conn,
+      &mut callbacks,
+      newbie.id,
+      AuthedRequest::AthCreateOrg("org".to_string()),
+    );
+    assert!(matches!(r, Ok(UserResponse::UrpEmailNotVerified)));
+    let r = authed(
+      &config,
+      &conn,
+      &mut callbacks,
+      newbie.id,
+      AuthedRequest::AthGroupRequest(GroupRequest::GrqCreateGroup("group".to_string())),
+    );
+    assert!(matches!(r, Ok(UserResponse::UrpEmailNotVerified)));
+    assert!(matches!(
+      authed(
+        &config,
+        &conn,
+        &mut callbacks,
+        newbie.id,
+        AuthedRequest::AthGetProfile
+      ),
+      Ok(UserResponse::UrpProfile(_))
+    ));
+
+    dbfun::set_email_verified(&conn, newbie.id).unwrap();
+    assert!(!dbfun::email_restricted(&conn, &config, newbie.id).unwrap());
+    let r = authed(
+      &config,
+      &conn,
+      &mut callbacks,
+      newbie.id,
+      AuthedRequest::AthCreateOrg("org".to_string()),
+    );
+    assert!(matches!(r, Ok(UserResponse::UrpOrg(_))));
+  }
+
+  #[test]
+  fn test_blocklist_file() {
+    let path = std::env::temp_dir().join(format!("orgauth-blocklist-{}", Uuid::new_v4()));
+    let write = |text: &str, mtime: u64| {
+      std::fs::write(&path, text).unwrap();
+      std::fs::File::options()
+        .write(true)
+        .open(&path)
+        .unwrap()
+        .set_modified(SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(mtime))
+        .unwrap();
+    };
+    write("# spam\nspam.com\n", 1000);
+    assert!(blocklist_file_matches(&path, "spam.com"));
+    assert!(!blocklist_file_matches(&path, "junk.com"));
+    // edits are picked up.
+    write("junk.com\n", 2000);
+    assert!(!blocklist_file_matches(&path, "spam.com"));
+    assert!(blocklist_file_matches(&path, "junk.com"));
+    // a missing file blocks nothing, and is read again once it's back.
+    std::fs::remove_file(&path).unwrap();
+    assert!(!blocklist_file_matches(&path, "junk.com"));
+    write("junk.com\n", 2000);
+    assert!(blocklist_file_matches(&path, "junk.com"));
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_import_users() {
+    let (conn, path) = test_db("test_import_users");
+    let mut config = test_config(path.as_path());
+    config.email_domain_blocklist = vec!["spam.com".to_string()];
+    let mut callbacks = test_callbacks();
+    let admin = test_user(&conn, "admin", true);
+    let csv = "name,email\n\
+      bob,bob@example.com\n\
+      BOB,bob2@example.com\n\
+      sue,admin@example.com\n\
+      cal,cal@example.com,true\n\
+      dan,dan@spam.com\n";
+    let import = |callbacks: &mut Callbacks, mode, dry_run| {
+      let iu = ImportUsers {
+        source: ImportSource::IsCsv(csv.to_string()),
+        mode,
+        dry_run,
+      };
+      import_users(&conn, &config, admin, callbacks, &iu).unwrap()
+    };
+
+    let rows = import(&mut callbacks, ImportMode::ImCreate, true);
+    assert_eq!(rows.len(), 5);
+    assert!(matches!(rows[0].outcome, ImportOutcome::IoValid));
+    assert!(matches!(
+      rows[1].outcome,
+      ImportOutcome::IoInvalidUserName(UsernameError::UneTaken)
+    ));
+    assert!(matches!(rows[2].outcome, ImportOutcome::IoEmailInUse));
+    assert!(matches!(rows[4].outcome, ImportOutcome::IoError(_)));
+    assert!(dbfun::read_user_by_name(&conn, "bob").is_err());
+
+    // invites go through the same email checks.
+    let rows = import(&mut callbacks, ImportMode::ImInvite, false);
+    assert!(matches!(rows[0].outcome, ImportOutcome::IoInvited(_)));
+    assert!(matches!(rows[3].outcome, ImportOutcome::IoError(_)));
+    assert!(matches!(rows[4].outcome, ImportOutcome::IoError(_)));
+
+    // a failed row leaves nothing behind, and the connection is out of its savepoints.
+    let rows = import(&mut callbacks, ImportMode::ImCreate, false);
+    assert!(matches!(rows[3].outcome, ImportOutcome::IoCreated(_)));
+    assert!(dbfun::read_user_by_name(&conn, "cal").unwrap().admin);
+    callbacks.on_new_user = Box::new(|_, _, _, _, _, _| Err("no room".into()));
+    let rows = import_users(
+      &conn,
+      &config,
+      admin,
+      &mut callbacks,
+      &ImportUsers {
+        source: ImportSource::IsCsv("eve,eve@example.com\n".to_string()),
+        mode: ImportMode::ImCreate,
+        dry_run: false,
+      },
+    )
+    .unwrap();
+    assert!(matches!(rows[0].outcome, ImportOutcome::IoError(_)));
+    assert!(dbfun::read_user_by_name(&conn, "eve").is_err());
+    assert!(conn.is_autocommit());
+  }
+
+  #[test]
+  fn test_bulk_user_op() {
+    let (conn, path) = test_db("test_bulk_user_op");
+    let config = test_config(path.as_path());
+    let mut callbacks = test_callbacks();
+    let admin = test_user(&conn, "admin", true);
+    let bob = test_user(&conn, "bob", false);
+    let mut op = |op, uid| bulk_user_op(&conn, &config, admin, &mut callbacks, op, uid).unwrap();
+    assert!(matches!(
+      op(BulkOp::BopDeactivate, bob),
+      BulkOutcome::BorDone
+    ));
+    assert!(!dbfun::read_user_by_id(&conn, bob).unwrap().active);
+    assert!(matches!(op(BulkOp::BopActivate, bob), BulkOutcome::BorDone));
+    assert!(matches!(
+      op(BulkOp::BopDelete, admin),
+      BulkOutcome::BorError(_)
+    ));
+    assert!(matches!(op(BulkOp::BopDelete, bob), BulkOutcome::BorDone));
+    assert!(matches!(
+      op(BulkOp::BopLogout, bob),
+      BulkOutcome::BorNotFound
+    ));
+    assert!(matches!(
+      op(BulkOp::BopLogout, UserId::Uid(999)),
+      BulkOutcome::BorNotFound
+    ));
+  }
+
+  #[test]
+  fn test_delete_account_callback_error() {
+    let (conn, path) = test_db("test_delete_account_callback_error");
+    let config = test_config(path.as_path());
+    let uid = test_user(&conn, "quitter", false);
+    let mut callbacks = test_callbacks();
+    callbacks.on_delete_user = Box::new(|_, _| Err("app data won't go".into()));
+    let da = || {
+      AuthedRequest::AthDeleteAccount(DeleteAccount {
+        pwd: "pwd".to_string(),
+      })
+    };
+    assert!(authed(&config, &conn, &mut callbacks, uid, da()).is_err());
+
+    // rolled back, so the connection takes new transactions.
+    assert!(dbfun::read_user_by_id(&conn, uid).is_ok());
+    callbacks.on_delete_user = Box::new(|_, _| Ok(true));
+    assert!(matches!(
+      authed(&config, &conn, &mut callbacks, uid, da()),
+      Ok(UserResponse::UrpAccountDeleted)
+    ));
+    assert!(dbfun::read_user_by_id(&conn, uid).is_err());
+  }
+}
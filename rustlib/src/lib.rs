@@ -6,6 +6,7 @@ pub mod email;
 pub mod endpoints;
 pub mod error;
 pub mod migrations;
+pub mod username;
 pub mod util;
 
 #[cfg(test)]
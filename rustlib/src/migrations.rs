@@ -1,6 +1,9 @@
 use crate::error;
+use crate::username;
+use crate::util::{normalize_email, now};
 use barrel::backend::Sqlite;
 use barrel::{types, Migration};
+use log::warn;
 use rusqlite::{params, Connection};
 use std::path::Path;
 
@@ -347,3 +350,981 @@ pub fn udpate8(dbfile: &Path) -> Result<(), error::Error> {
 
   Ok(())
 }
+
+pub fn udpate9(dbfile: &Path) -> Result<(), error::Error> {
+  // db connection without foreign key checking.
+  let conn = Connection::open(dbfile)?;
+  conn.execute("PRAGMA foreign_keys = false;", params![])?;
+
+  let mut m = Migration::new();
+
+  // named roles.
+  m.create_table("orgauth_role", |t| {
+    t.add_column(
+      "id",
+      types::integer()
+        .primary(true)
+        .increments(true)
+        .nullable(false),
+    );
+    t.add_column("name", types::text().nullable(false).unique(true));
+    t.add_column("description", types::text().nullable(true));
+    t.add_column("createdate", types::integer().nullable(false));
+  });
+
+  // permissions granted by each role.
+  m.create_table("orgauth_permission", |t| {
+    t.add_column(
+      "role",
+      types::foreign(
+        "orgauth_role",
+        "id",
+        types::ReferentialAction::Restrict,
+        types::ReferentialAction::Restrict,
+      )
+      .nullable(false),
+    );
+    t.add_column("name", types::text().nullable(false));
+    t.add_index(
+      "orgauth_permissionunq",
+      types::index(vec!["role", "name"]).unique(true),
+    );
+  });
+
+  // role assignments.
+  m.create_table("orgauth_user_role", |t| {
+    t.add_column(
+      "user",
+      types::foreign(
+        "orgauth_user",
+        "id",
+        types::ReferentialAction::Restrict,
+        types::ReferentialAction::Restrict,
+      )
+      .nullable(false),
+    );
+    t.add_column(
+      "role",
+      types::foreign(
+        "orgauth_role",
+        "id",
+        types::ReferentialAction::Restrict,
+        types::ReferentialAction::Restrict,
+      )
+      .nullable(false),
+    );
+    t.add_index(
+      "orgauth_user_roleunq",
+      types::index(vec!["user", "role"]).unique(true),
+    );
+  });
+
+  conn.execute_batch(m.make::<Sqlite>().as_str())?;
+
+  // built-in admin role, with the existing admins as members.
+  conn.execute(
+    "insert into orgauth_role (name, description, createdate)
+      values ('admin', 'built-in administrator role', ?1)",
+    params![now()?],
+  )?;
+  let admin_role = conn.last_insert_rowid();
+
+  conn.execute(
+    "insert into orgauth_permission (role, name) values (?1, 'admin')",
+    params![admin_role],
+  )?;
+
+  conn.execute(
+    "insert into orgauth_user_role (user, role)
+      select id, ?1 from orgauth_user where admin = 1",
+    params![admin_role],
+  )?;
+
+  Ok(())
+}
+
+pub fn udpate10(dbfile: &Path) -> Result<(), error::Error> {
+  // db connection without foreign key checking.
+  let conn = Connection::open(dbfile)?;
+  conn.execute("PRAGMA foreign_keys = false;", params![])?;
+
+  let mut m = Migration::new();
+
+  m.create_table("orgauth_org", |t| {
+    t.add_column(
+      "id",
+      types::integer()
+        .primary(true)
+        .increments(true)
+        .nullable(false),
+    );
+    t.add_column("name", types::text().nullable(false).unique(true));
+    t.add_column(
+      "creator",
+      types::foreign(
+        "orgauth_user",
+        "id",
+        types::ReferentialAction::Restrict,
+        types::ReferentialAction::Restrict,
+      )
+      .nullable(true),
+    );
+    t.add_column("createdate", types::integer().nullable(false));
+  });
+
+  // role is one of 'owner', 'admin', 'member'.
+  m.create_table("orgauth_org_member", |t| {
+    t.add_column(
+      "org",
+      types::foreign(
+        "orgauth_org",
+        "id",
+        types::ReferentialAction::Restrict,
+        types::ReferentialAction::Restrict,
+      )
+      .nullable(false),
+    );
+    t.add_column(
+      "user",
+      types::foreign(
+        "orgauth_user",
+        "id",
+        types::ReferentialAction::Restrict,
+        types::ReferentialAction::Restrict,
+      )
+      .nullable(false),
+    );
+    t.add_column("role", types::text().nullable(false));
+    t.add_column("createdate", types::integer().nullable(false));
+    t.add_index(
+      "orgauth_org_memberunq",
+      types::index(vec!["org", "user"]).unique(true),
+    );
+  });
+
+  // invites can bring the new user into an org.
+  m.change_table("orgauth_user_invite", |t| {
+    t.add_column(
+      "org",
+      types::foreign(
+        "orgauth_org",
+        "id",
+        types::ReferentialAction::Restrict,
+        types::ReferentialAction::Restrict,
+      )
+      .nullable(true),
+    );
+  });
+
+  conn.execute_batch(m.make::<Sqlite>().as_str())?;
+
+  Ok(())
+}
+
+pub fn udpate11(dbfile: &Path) -> Result<(), error::Error> {
+  // db connection without foreign key checking.
+  let conn = Connection::open(dbfile)?;
+  conn.execute("PRAGMA foreign_keys = false;", params![])?;
+
+  let mut m = Migration::new();
+
+  m.create_table("orgauth_group", |t| {
+    t.add_column(
+      "id",
+      types::integer()
+        .primary(true)
+        .increments(true)
+        .nullable(false),
+    );
+    t.add_column("name", types::text().nullable(false));
+    // null if the owner was deleted.
+    t.add_column(
+      "owner",
+      types::foreign(
+        "orgauth_user",
+        "id",
+        types::ReferentialAction::Restrict,
+        types::ReferentialAction::Restrict,
+      )
+      .nullable(true),
+    );
+    t.add_column("createdate", types::integer().nullable(false));
+  });
+
+  m.create_table("orgauth_group_member", |t| {
+    t.add_column(
+      "grp",
+      types::foreign(
+        "orgauth_group",
+        "id",
+        types::ReferentialAction::Restrict,
+        types::ReferentialAction::Restrict,
+      )
+      .nullable(false),
+    );
+    t.add_column(
+      "user",
+      types::foreign(
+        "orgauth_user",
+        "id",
+        types::ReferentialAction::Restrict,
+        types::ReferentialAction::Restrict,
+      )
+      .nullable(false),
+    );
+    t.add_index(
+      "orgauth_group_memberunq",
+      types::index(vec!["grp", "user"]).unique(true),
+    );
+  });
+
+  // members of child are also members of parent.
+  m.create_table("orgauth_group_nesting", |t| {
+    t.add_column(
+      "parent",
+      types::foreign(
+        "orgauth_group",
+        "id",
+        types::ReferentialAction::Restrict,
+        types::ReferentialAction::Restrict,
+      )
+      .nullable(false),
+    );
+    t.add_column(
+      "child",
+      types::foreign(
+        "orgauth_group",
+        "id",
+        types::ReferentialAction::Restrict,
+        types::ReferentialAction::Restrict,
+      )
+      .nullable(false),
+    );
+    t.add_index(
+      "orgauth_group_nestingunq",
+      types::index(vec!["parent", "child"]).unique(true),
+    );
+  });
+
+  conn.execute_batch(m.make::<Sqlite>().as_str())?;
+
+  Ok(())
+}
+
+pub fn udpate12(dbfile: &Path) -> Result<(), error::Error> {
+  // db connection without foreign key checking.
+  let conn = Connection::open(dbfile)?;
+  conn.execute("PRAGMA foreign_keys = false;", params![])?;
+
+  let mut m = Migration::new();
+
+  // built-in profile fields, one row per user.
+  m.create_table("orgauth_user_profile", |t| {
+    t.add_column(
+      "user",
+      types::foreign(
+        "orgauth_user",
+        "id",
+        types::ReferentialAction::Restrict,
+        types::ReferentialAction::Restrict,
+      )
+      .nullable(false)
+      .unique(true),
+    );
+    t.add_column("display_name", types::text().nullable(true));
+    t.add_column("avatar", types::text().nullable(true));
+    t.add_column("locale", types::text().nullable(true));
+    t.add_column("timezone", types::text().nullable(true));
+    t.add_column("bio", types::text().nullable(true));
+  });
+
+  // host app declared profile fields.
+  m.create_table("orgauth_user_profile_field", |t| {
+    t.add_column(
+      "user",
+      types::foreign(
+        "orgauth_user",
+        "id",
+        types::ReferentialAction::Restrict,
+        types::ReferentialAction::Restrict,
+      )
+      .nullable(false),
+    );
+    t.add_column("name", types::text().nullable(false));
+    t.add_column("value", types::text().nullable(false));
+    t.add_index(
+      "orgauth_user_profile_fieldunq",
+      types::index(vec!["user", "name"]).unique(true),
+    );
+  });
+
+  conn.execute_batch(m.make::<Sqlite>().as_str())?;
+
+  Ok(())
+}
+
+pub fn udpate13(dbfile: &Path) -> Result<(), error::Error> {
+  // db connection without foreign key checking.
+  let conn = Connection::open(dbfile)?;
+  conn.execute("PRAGMA foreign_keys = false;", params![])?;
+
+  let mut m = Migration::new();
+
+  // confusable skeleton of the name, for lookalike checks.
+  m.change_table("orgauth_user", |t| {
+    t.add_column("name_skeleton", types::text().nullable(true));
+    t.add_index(
+      "orgauth_user_name_skeleton",
+      types::index(vec!["name_skeleton"]),
+    );
+  });
+
+  conn.execute_batch(m.make::<Sqlite>().as_str())?;
+
+  let mut pstmt = conn.prepare("select id, name from orgauth_user order by id")?;
+  let users: Vec<(i64, String)> = pstmt
+    .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?
+    .filter_map(|x| x.ok())
+    .collect();
+
+  for (id, name) in users {
+    let normalized = username::normalize(name.as_str());
+    if normalized != name {
+      // names were only lowercased before; renormalize unless that would collide.
+      // Colliding names stay as they are until udpate15 renames them.
+      let collisions: i64 = conn.query_row(
+        "select count(*) from orgauth_user where name = ?1",
+        params![normalized],
+        |row| row.get(0),
+      )?;
+      if collisions == 0 {
+        conn.execute(
+          "update orgauth_user set name = ?1 where id = ?2",
+          params![normalized, id],
+        )?;
+      } else {
+        warn!(
+          "user {} name '{}' not renormalized; '{}' already exists",
+          id, name, normalized
+        );
+      }
+    }
+
+    conn.execute(
+      "update orgauth_user set name_skeleton = ?1 where id = ?2",
+      params![username::name_skeleton(name.as_str()), id],
+    )?;
+  }
+
+  Ok(())
+}
+
+pub fn udpate14(dbfile: &Path) -> Result<(), error::Error> {
+  // db connection without foreign key checking.
+  let conn = Connection::open(dbfile)?;
+  conn.execute("PRAGMA foreign_keys = false;", params![])?;
+
+  let mut m = Migration::new();
+
+  // trimmed, lowercased email for login by email.  null for blank or duplicate emails.
+  m.change_table("orgauth_user", |t| {
+    t.add_column("email_normalized", types::text().nullable(true));
+  });
+
+  conn.execute_batch(m.make::<Sqlite>().as_str())?;
+
+  let mut pstmt = conn.prepare("select id, name, email from orgauth_user order by id")?;
+  let users: Vec<(i64, String, String)> = pstmt
+    .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+    .filter_map(|x| x.ok())
+    .collect();
+
+  let mut seen = std::collections::HashMap::<String, String>::new();
+  for (id, name, email) in users {
+    if let Some(ne) = normalize_email(email.as_str()) {
+      match seen.get(&ne) {
+        Some(first) => {
+          // the first user keeps the email for login; the rest need fixing by an admin.
+          warn!(
+            "duplicate email '{}': user '{}' conflicts with user '{}'; not usable for login",
+            ne, name, first
+          );
+        }
+        None => {
+          conn.execute(
+            "update orgauth_user set email_normalized = ?1 where id = ?2",
+            params![ne, id],
+          )?;
+          seen.insert(ne, name);
+        }
+      }
+    }
+  }
+
+  conn.execute(
+    "create unique index orgauth_user_email_unq on orgauth_user (email_normalized)",
+    params![],
+  )?;
+
+  Ok(())
+}
+
+pub fn udpate15(dbfile: &Path) -> Result<(), error::Error> {
+  // db connection without foreign key checking.
+  let conn = Connection::open(dbfile)?;
+  conn.execute("PRAGMA foreign_keys = false;", params![])?;
+
+  let mut m = Migration::new();
+
+  // previous names, for reservation and resolving old links.
+  m.create_table("orgauth_username_history", |t| {
+    t.add_column(
+      "user",
+      types::foreign(
+        "orgauth_user",
+        "id",
+        types::ReferentialAction::Restrict,
+        types::ReferentialAction::Restrict,
+      )
+      .nullable(false),
+    );
+    t.add_column("name", types::text().nullable(false));
+    t.add_column("name_skeleton", types::text().nullable(false));
+    t.add_column("changedate", types::integer().nullable(false));
+    t.add_index("orgauth_username_history_name", types::index(vec!["name"]));
+  });
+
+  conn.execute_batch(m.make::<Sqlite>().as_str())?;
+
+  // names left unnormalized by udpate13 collide with another user's name, so
+  // lookups by that name find the other user.  Rename them to name_id, and keep
+  // the old name in the history, where resolve_user_by_name and login still
+  // find it.  Users aren't notified; the log lists who was renamed.
+  let now = now()?;
+  let mut pstmt = conn.prepare("select id, name from orgauth_user order by id")?;
+  let users: Vec<(i64, String)> = pstmt
+    .query_map(params![], |row| Ok((row.get(0)?, row.get(1)?)))?
+    .filter_map(|x| x.ok())
+    .collect();
+
+  let name_taken = |name: &str| -> Result<bool, rusqlite::Error> {
+    let count: i64 = conn.query_row(
+      "select count(*) from orgauth_user where name = ?1",
+      params![name],
+      |row| row.get(0),
+    )?;
+    Ok(count > 0)
+  };
+
+  for (id, name) in users {
+    let normalized = username::normalize(name.as_str());
+    if normalized == name {
+      continue;
+    }
+    let mut newname = format!("{}_{}", normalized, id);
+    while name_taken(newname.as_str())? {
+      newname.push('_');
+    }
+    warn!(
+      "user {} name '{}' renamed to '{}'; '{}' already exists",
+      id, name, newname, normalized
+    );
+    conn.execute(
+      "update orgauth_user set name = ?1, name_skeleton = ?2 where id = ?3",
+      params![newname, username::name_skeleton(newname.as_str()), id],
+    )?;
+    conn.execute(
+      "insert into orgauth_username_history (user, name, name_skeleton, changedate)
+        values (?1, ?2, ?3, ?4)",
+      params![id, name, username::name_skeleton(name.as_str()), now],
+    )?;
+  }
+
+  Ok(())
+}
+
+pub fn udpate16(dbfile: &Path) -> Result<(), error::Error> {
+  // db connection without foreign key checking.
+  let conn = Connection::open(dbfile)?;
+  conn.execute("PRAGMA foreign_keys = false;", params![])?;
+
+  let mut m = Migration::new();
+
+  // soft delete; null for live users.
+  m.change_table("orgauth_user", |t| {
+    t.add_column("deleted_date", types::integer().nullable(true));
+  });
+
+  conn.execute_batch(m.make::<Sqlite>().as_str())?;
+
+  Ok(())
+}
+
+pub fn udpate17(dbfile: &Path) -> Result<(), error::Error> {
+  // db connection without foreign key checking.
+  let conn = Connection::open(dbfile)?;
+  conn.execute("PRAGMA foreign_keys = false;", params![])?;
+
+  let mut m = Migration::new();
+
+  // at most one suspension per user.  Removed when lifted or expired.
+  m.create_table("orgauth_user_suspension", |t| {
+    t.add_column(
+      "user",
+      types::foreign(
+        "orgauth_user",
+        "id",
+        types::ReferentialAction::Restrict,
+        types::ReferentialAction::Restrict,
+      )
+      .nullable(false)
+      .unique(true),
+    );
+    t.add_column("reason", types::text().nullable(false));
+    t.add_column("until", types::integer().nullable(true));
+    t.add_column(
+      "suspended_by",
+      types::foreign(
+        "orgauth_user",
+        "id",
+        types::ReferentialAction::Restrict,
+        types::ReferentialAction::Restrict,
+      )
+      .nullable(true),
+    );
+    t.add_column("createdate", types::integer().nullable(false));
+  });
+
+  conn.execute_batch(m.make::<Sqlite>().as_str())?;
+
+  Ok(())
+}
+
+pub fn udpate18(dbfile: &Path) -> Result<(), error::Error> {
+  // db connection without foreign key checking.
+  let conn = Connection::open(dbfile)?;
+  conn.execute("PRAGMA foreign_keys = false;", params![])?;
+
+  let mut m = Migration::new();
+
+  // account activity, and who invited the user.
+  m.change_table("orgauth_user", |t| {
+    t.add_column(
+      "creator",
+      types::foreign(
+        "orgauth_user",
+        "id",
+        types::ReferentialAction::Restrict,
+        types::ReferentialAction::Restrict,
+      )
+      .nullable(true),
+    );
+  });
+  m.change_table("orgauth_user", |t| {
+    t.add_column("last_login", types::integer().nullable(true));
+  });
+  m.change_table("orgauth_user", |t| {
+    t.add_column("last_seen", types::integer().nullable(true));
+  });
+  m.change_table("orgauth_user", |t| {
+    t.add_column("login_count", types::integer().nullable(false).default(0));
+  });
+
+  conn.execute_batch(m.make::<Sqlite>().as_str())?;
+
+  Ok(())
+}
+
+pub fn udpate19(dbfile: &Path) -> Result<(), error::Error> {
+  // db connection without foreign key checking.
+  let conn = Connection::open(dbfile)?;
+  conn.execute("PRAGMA foreign_keys = false;", params![])?;
+
+  let mut m = Migration::new();
+
+  // impersonation tokens: the admin, the admin's own token to return to, and
+  // a hard expiration.
+  m.change_table("orgauth_token", |t| {
+    t.add_column(
+      "impersonator",
+      types::foreign(
+        "orgauth_user",
+        "id",
+        types::ReferentialAction::Restrict,
+        types::ReferentialAction::Restrict,
+      )
+      .nullable(true),
+    );
+  });
+  m.change_table("orgauth_token", |t| {
+    t.add_column("admin_token", types::text().nullable(true));
+  });
+  m.change_table("orgauth_token", |t| {
+    t.add_column("expiredate", types::integer().nullable(true));
+  });
+
+  m.create_table("orgauth_audit_log", |t| {
+    t.add_column(
+      "id",
+      types::integer()
+        .primary(true)
+        .increments(true)
+        .nullable(false),
+    );
+    t.add_column(
+      "actor",
+      types::foreign(
+        "orgauth_user",
+        "id",
+        types::ReferentialAction::Restrict,
+        types::ReferentialAction::Restrict,
+      )
+      .nullable(true),
+    );
+    t.add_column(
+      "target",
+      types::foreign(
+        "orgauth_user",
+        "id",
+        types::ReferentialAction::Restrict,
+        types::ReferentialAction::Restrict,
+      )
+      .nullable(true),
+    );
+    t.add_column("event", types::text().nullable(false));
+    t.add_column("detail", types::text().nullable(true));
+    t.add_column("createdate", types::integer().nullable(false));
+    t.add_index("orgauth_audit_log_actor", types::index(vec!["actor"]));
+    t.add_index("orgauth_audit_log_target", types::index(vec!["target"]));
+  });
+
+  conn.execute_batch(m.make::<Sqlite>().as_str())?;
+
+  Ok(())
+}
+
+pub fn udpate20(dbfile: &Path) -> Result<(), error::Error> {
+  // db connection without foreign key checking.
+  let conn = Connection::open(dbfile)?;
+  conn.execute("PRAGMA foreign_keys = false;", params![])?;
+
+  let mut m = Migration::new();
+
+  // set for admin-created accounts with temporary passwords.
+  m.change_table("orgauth_user", |t| {
+    t.add_column(
+      "must_change_password",
+      types::boolean().nullable(false).default(false),
+    );
+  });
+
+  conn.execute_batch(m.make::<Sqlite>().as_str())?;
+
+  Ok(())
+}
+
+pub fn udpate21(dbfile: &Path) -> Result<(), error::Error> {
+  // db connection without foreign key checking.
+  let conn = Connection::open(dbfile)?;
+  conn.execute("PRAGMA foreign_keys = false;", params![])?;
+
+  let mut m = Migration::new();
+
+  // admin overrides of Config values.  value is json.
+  m.create_table("orgauth_settings", |t| {
+    t.add_column("name", types::text().nullable(false).unique(true));
+    t.add_column("value", types::text().nullable(false));
+  });
+
+  conn.execute_batch(m.make::<Sqlite>().as_str())?;
+
+  Ok(())
+}
+
+pub fn udpate22(dbfile: &Path) -> Result<(), error::Error> {
+  // db connection without foreign key checking.
+  let conn = Connection::open(dbfile)?;
+  conn.execute("PRAGMA foreign_keys = false;", params![])?;
+
+  let mut m = Migration::new();
+
+  // per-invite expiration and multi-use invites.  Existing invites keep using
+  // invite_token_expiration_ms, with a null expiredate.
+  m.change_table("orgauth_user_invite", |t| {
+    t.add_column("expiredate", types::integer().nullable(true));
+  });
+  m.change_table("orgauth_user_invite", |t| {
+    t.add_column("max_uses", types::integer().nullable(false).default(1));
+  });
+  m.change_table("orgauth_user_invite", |t| {
+    t.add_column("uses", types::integer().nullable(false).default(0));
+  });
+
+  // who joined through which invite.  Kept after the invite is used up.
+  m.create_table("orgauth_invite_use", |t| {
+    t.add_column("token", types::text().nullable(false));
+    t.add_column(
+      "user",
+      types::foreign(
+        "orgauth_user",
+        "id",
+        types::ReferentialAction::Restrict,
+        types::ReferentialAction::Restrict,
+      )
+      .nullable(false),
+    );
+    t.add_column(
+      "creator",
+      types::foreign(
+        "orgauth_user",
+        "id",
+        types::ReferentialAction::Restrict,
+        types::ReferentialAction::Restrict,
+      )
+      .nullable(true),
+    );
+    t.add_column("usedate", types::integer().nullable(false));
+    t.add_index("orgauth_invite_use_token", types::index(vec!["token"]));
+  });
+
+  conn.execute_batch(m.make::<Sqlite>().as_str())?;
+
+  Ok(())
+}
+
+pub fn udpate23(dbfile: &Path) -> Result<(), error::Error> {
+  // db connection without foreign key checking.
+  let conn = Connection::open(dbfile)?;
+  conn.execute("PRAGMA foreign_keys = false;", params![])?;
+
+  let mut m = Migration::new();
+
+  // per-user invite quota overrides.  Users without a row get the config defaults.
+  m.create_table("orgauth_invite_quota", |t| {
+    t.add_column(
+      "user",
+      types::foreign(
+        "orgauth_user",
+        "id",
+        types::ReferentialAction::Restrict,
+        types::ReferentialAction::Restrict,
+      )
+      .nullable(false)
+      .unique(true),
+    );
+    // null for unlimited.
+    t.add_column("quota", types::integer().nullable(true));
+    // null for a lifetime quota.
+    t.add_column("period_ms", types::integer().nullable(true));
+    t.add_column("revoked", types::boolean().nullable(false).default(false));
+  });
+
+  // invites issued, for counting against quotas.  Kept after invites are used or purged.
+  m.create_table("orgauth_invite_issue", |t| {
+    t.add_column(
+      "creator",
+      types::foreign(
+        "orgauth_user",
+        "id",
+        types::ReferentialAction::Restrict,
+        types::ReferentialAction::Restrict,
+      )
+      .nullable(false),
+    );
+    t.add_column("createdate", types::integer().nullable(false));
+    // the invite's max_uses; each use counts against the quota.
+    t.add_column("uses", types::integer().nullable(false).default(1));
+    t.add_index(
+      "orgauth_invite_issue_creator",
+      types::index(vec!["creator", "createdate"]),
+    );
+  });
+
+  conn.execute_batch(m.make::<Sqlite>().as_str())?;
+
+  Ok(())
+}
+
+pub fn udpate24(dbfile: &Path) -> Result<(), error::Error> {
+  // db connection without foreign key checking.
+  let conn = Connection::open(dbfile)?;
+  conn.execute("PRAGMA foreign_keys = false;", params![])?;
+
+  let mut m = Migration::new();
+
+  // self-registered users awaiting admin approval, with Config.registration_approval.
+  m.change_table("orgauth_user", |t| {
+    t.add_column(
+      "approval_pending",
+      types::boolean().nullable(false).default(false),
+    );
+  });
+
+  conn.execute_batch(m.make::<Sqlite>().as_str())?;
+
+  Ok(())
+}
+
+pub fn udpate25(dbfile: &Path) -> Result<(), error::Error> {
+  // db connection without foreign key checking.
+  let conn = Connection::open(dbfile)?;
+  conn.execute("PRAGMA foreign_keys = false;", params![])?;
+
+  let mut m = Migration::new();
+
+  // one record per completed email change, for the revert link sent to the old address.
+  m.create_table("orgauth_email_revert", |t| {
+    t.add_column(
+      "user",
+      types::foreign(
+        "orgauth_user",
+        "id",
+        types::ReferentialAction::Restrict,
+        types::ReferentialAction::Restrict,
+      )
+      .nullable(false),
+    );
+    t.add_column("old_email", types::text().nullable(false));
+    t.add_column("new_email", types::text().nullable(false));
+    t.add_column("token", types::text().nullable(false));
+    t.add_column("tokendate", types::integer().nullable(false));
+    t.add_index(
+      "orgauth_email_revertunq",
+      types::index(vec!["user", "token"]).unique(true),
+    );
+  });
+
+  conn.execute_batch(m.make::<Sqlite>().as_str())?;
+
+  Ok(())
+}
+
+pub fn udpate26(dbfile: &Path) -> Result<(), error::Error> {
+  // db connection without foreign key checking.
+  let conn = Connection::open(dbfile)?;
+  conn.execute("PRAGMA foreign_keys = false;", params![])?;
+
+  let mut m = Migration::new();
+
+  // when the last registration email went out, for rate limiting resends.
+  m.change_table("orgauth_user", |t| {
+    t.add_column("registration_sent", types::integer().nullable(true));
+  });
+
+  conn.execute_batch(m.make::<Sqlite>().as_str())?;
+
+  Ok(())
+}
+
+pub fn udpate27(dbfile: &Path) -> Result<(), error::Error> {
+  // db connection without foreign key checking.
+  let conn = Connection::open(dbfile)?;
+  conn.execute("PRAGMA foreign_keys = false;", params![])?;
+
+  let mut m = Migration::new();
+
+  // when the user's current email was verified.  Cleared when the email changes.
+  m.change_table("orgauth_user", |t| {
+    t.add_column("email_verified", types::integer().nullable(true));
+  });
+
+  // one record per verification email sent.
+  m.create_table("orgauth_email_verify", |t| {
+    t.add_column(
+      "user",
+      types::foreign(
+        "orgauth_user",
+        "id",
+        types::ReferentialAction::Restrict,
+        types::ReferentialAction::Restrict,
+      )
+      .nullable(false),
+    );
+    t.add_column("email", types::text().nullable(false));
+    t.add_column("token", types::text().nullable(false));
+    t.add_column("tokendate", types::integer().nullable(false));
+    t.add_index(
+      "orgauth_email_verifyunq",
+      types::index(vec!["user", "token"]).unique(true),
+    );
+  });
+
+  // set when the invite link was actually emailed to the invite address.
+  m.change_table("orgauth_user_invite", |t| {
+    t.add_column("emailed", types::boolean().default(false));
+  });
+
+  conn.execute_batch(m.make::<Sqlite>().as_str())?;
+
+  // registered users got here through the registration link, or by being
+  // trusted some other way.
+  conn.execute(
+    "update orgauth_user set email_verified = createdate where registration_key is null",
+    params![],
+  )?;
+
+  Ok(())
+}
+
+#[cfg(test)]
+pub mod tests {
+  use super::*;
+  use std::path::PathBuf;
+
+  type Migration = fn(&Path) -> Result<(), error::Error>;
+
+  const MIGRATIONS: [Migration; 27] = [
+    udpate1, udpate2, udpate3, udpate4, udpate5, udpate6, udpate7, udpate8, udpate9, udpate10,
+    udpate11, udpate12, udpate13, udpate14, udpate15, udpate16, udpate17, udpate18, udpate19,
+    udpate20, udpate21, udpate22, udpate23, udpate24, udpate25, udpate26, udpate27,
+  ];
+
+  fn memory_db(name: &str) -> (Connection, PathBuf) {
+    let path = PathBuf::from(format!("file:{}?mode=memory&cache=shared", name));
+    let conn = crate::dbfun::connection_open(path.as_path()).unwrap();
+    (conn, path)
+  }
+
+  // a fresh in-memory db with every migration applied, and its path for
+  // Config.db.  Each name is a separate db, which lasts while a connection to
+  // it stays open.
+  pub fn test_db(name: &str) -> (Connection, PathBuf) {
+    let (conn, path) = memory_db(name);
+    for m in MIGRATIONS {
+      m(path.as_path()).unwrap();
+    }
+    (conn, path)
+  }
+
+  #[test]
+  fn test_renormalize_collision() {
+    let (conn, path) = memory_db("test_renormalize_collision");
+    for m in &MIGRATIONS[..12] {
+      m(path.as_path()).unwrap();
+    }
+    for name in ["strasse", "straße"] {
+      conn
+        .execute(
+          "insert into orgauth_user (name, hashwd, salt, email, createdate, uuid, admin, active)
+            values (?1, 'x', 'x', ?1, 0, ?2, 0, 1)",
+          params![name, uuid::Uuid::new_v4().to_string()],
+        )
+        .unwrap();
+    }
+    for m in &MIGRATIONS[12..] {
+      m(path.as_path()).unwrap();
+    }
+
+    let renamed = crate::dbfun::read_user_by_id(&conn, crate::data::UserId::Uid(2)).unwrap();
+    assert_eq!(renamed.name, "strasse_2");
+    // the old name still finds the renamed user.
+    let u = crate::dbfun::resolve_user_by_name(&conn, "straße").unwrap();
+    assert_eq!(u.id, renamed.id);
+    let u =
+      crate::dbfun::read_user_by_login_id(&conn, crate::data::LoginIdentifier::Username, "straße")
+        .unwrap();
+    assert_eq!(u.id, renamed.id);
+    let u = crate::dbfun::resolve_user_by_name(&conn, "Strasse").unwrap();
+    assert_eq!(*u.id.to_i64(), 1);
+  }
+}
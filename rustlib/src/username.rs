@@ -0,0 +1,101 @@
+use crate::data::{UsernameError, UsernamePolicy};
+use caseless::default_case_fold_str;
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::skeleton;
+
+// trim, NFKC normalize and case fold.  Names are stored and looked up in this form.
+pub fn normalize(name: &str) -> String {
+  let nfkc = name.trim().nfkc().collect::<String>();
+  default_case_fold_str(nfkc.as_str()).nfkc().collect()
+}
+
+// confusable skeleton of a normalized name; two names with the same skeleton
+// look alike, like 'paypal' and 'pаypal' (cyrillic a).
+pub fn name_skeleton(name: &str) -> String {
+  skeleton(normalize(name).as_str()).collect()
+}
+
+// check a normalized name against the policy.  Doesn't check the db for
+// existing or confusable names.
+pub fn validate(policy: &UsernamePolicy, name: &str) -> Result<(), UsernameError> {
+  let len = name.chars().count() as i64;
+  if len == 0 {
+    return Err(UsernameError::UneBlank);
+  }
+  if len < policy.min_length {
+    return Err(UsernameError::UneTooShort(policy.min_length));
+  }
+  if len > policy.max_length {
+    return Err(UsernameError::UneTooLong(policy.max_length));
+  }
+  if let Some(c) = name
+    .chars()
+    .find(|c| !(c.is_alphanumeric() || policy.allowed_punctuation.contains(*c)))
+  {
+    return Err(UsernameError::UneInvalidCharacter(c.to_string()));
+  }
+  if !name
+    .chars()
+    .next()
+    .map(|c| c.is_alphanumeric())
+    .unwrap_or(false)
+  {
+    return Err(UsernameError::UneInvalidStart);
+  }
+  let sk = name_skeleton(name);
+  if policy
+    .reserved_names
+    .iter()
+    .any(|r| normalize(r) == name || name_skeleton(r) == sk)
+  {
+    return Err(UsernameError::UneReserved);
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_normalize() {
+    assert_eq!(normalize("  Bob "), "bob");
+    // fullwidth letters and ligatures.
+    assert_eq!(normalize("ＡＢＣ"), "abc");
+    assert_eq!(normalize("ﬁsh"), "fish");
+    assert_eq!(normalize("STRASSE"), normalize("straße"));
+  }
+
+  #[test]
+  fn test_validate() {
+    let policy = UsernamePolicy::default();
+    assert_eq!(validate(&policy, "bob_smith"), Ok(()));
+    assert_eq!(validate(&policy, "josé"), Ok(()));
+    assert_eq!(validate(&policy, ""), Err(UsernameError::UneBlank));
+    assert_eq!(
+      validate(&policy, "b"),
+      Err(UsernameError::UneTooShort(policy.min_length))
+    );
+    assert_eq!(
+      validate(&policy, "bob smith"),
+      Err(UsernameError::UneInvalidCharacter(" ".to_string()))
+    );
+    assert_eq!(
+      validate(&policy, "_bob"),
+      Err(UsernameError::UneInvalidStart)
+    );
+    assert_eq!(validate(&policy, "admin"), Err(UsernameError::UneReserved));
+    // cyrillic 'а' in place of latin 'a'.
+    assert_eq!(
+      validate(&policy, normalize("аdmin").as_str()),
+      Err(UsernameError::UneReserved)
+    );
+  }
+
+  #[test]
+  fn test_skeleton() {
+    assert_eq!(name_skeleton("paypal"), name_skeleton("pаypal"));
+    assert_ne!(name_skeleton("paypal"), name_skeleton("paypals"));
+  }
+}
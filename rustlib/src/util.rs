@@ -1,3 +1,4 @@
+use crate::data::ImportUser;
 use crate::error;
 use rand;
 use rand::Rng;
@@ -40,6 +41,14 @@ pub fn get_rand_string(len: usize) -> String {
   rstr
 }
 
+// letters and digits only, for passwords people have to type.
+pub fn temp_password(len: usize) -> String {
+  let mut rng = rand::thread_rng();
+  (0..len)
+    .map(|_| rng.sample(rand::distributions::Alphanumeric))
+    .collect()
+}
+
 pub fn now() -> Result<i64, error::Error> {
   let nowsecs = SystemTime::now()
     .duration_since(SystemTime::UNIX_EPOCH)
@@ -62,9 +71,48 @@ pub fn show_time(seconds: i64) -> Option<String> {
   let f = time::format_description::parse("[year]-[month]-[day]T[hour]:[minute]:[second]");
 
   match f.map(|dtf| dt.format(&dtf)) {
-    Ok(Ok(dtf)) => return Some(format!("{}", dtf)),
-    _ => return None,
-  };
+    Ok(Ok(dtf)) => Some(dtf),
+    _ => None,
+  }
+}
+
+// for case insensitive email comparison.  None for blank or placeholder emails,
+// like the 'phantom' email of remote users.
+pub fn normalize_email(email: &str) -> Option<String> {
+  let e = email.trim().to_lowercase();
+  if e.contains('@') {
+    Some(e)
+  } else {
+    None
+  }
+}
+
+// the lowercased part after the @.
+pub fn email_domain(email: &str) -> Option<String> {
+  normalize_email(email).and_then(|e| e.rsplit('@').next().map(|d| d.to_string()))
+}
+
+// "example.com" matches just that domain, "*.example.com" any of its subdomains.
+pub fn domain_matches(pattern: &str, domain: &str) -> bool {
+  let pattern = pattern.trim().to_lowercase();
+  match pattern.strip_prefix("*.") {
+    Some(parent) => domain
+      .strip_suffix(parent)
+      .map(|sub| sub.len() > 1 && sub.ends_with('.'))
+      .unwrap_or(false),
+    None => pattern == domain,
+  }
+}
+
+pub fn valid_domain_pattern(pattern: &str) -> bool {
+  let p = pattern.trim();
+  let domain = p.strip_prefix("*.").unwrap_or(p);
+  domain.contains('.')
+    && !domain.starts_with('.')
+    && !domain.ends_with('.')
+    && domain
+      .chars()
+      .all(|c| c.is_alphanumeric() || c == '.' || c == '-')
 }
 
 pub fn is_token_expired(token_expiration_ms: i64, tokendate: i64) -> bool {
@@ -74,6 +122,47 @@ pub fn is_token_expired(token_expiration_ms: i64, tokendate: i64) -> bool {
   }
 }
 
+// name,email,admin,data per line.  data is the rest of the line, so it can
+// contain commas.  Returns 1 based line numbers with each parsed row.
+pub fn parse_import_csv(text: &str) -> Vec<(i64, Result<ImportUser, String>)> {
+  let mut rows = Vec::new();
+  for (i, line) in text.lines().enumerate() {
+    if line.trim() == "" {
+      continue;
+    }
+    let fields = line.splitn(4, ',').map(|f| f.trim()).collect::<Vec<&str>>();
+    let name = fields[0];
+    if i == 0 && (name.eq_ignore_ascii_case("name") || name.eq_ignore_ascii_case("username")) {
+      // header.
+      continue;
+    }
+    let email = fields.get(1).copied().unwrap_or("");
+    let admin = match fields.get(2).map(|a| a.to_lowercase()).as_deref() {
+      None | Some("") | Some("false") | Some("0") | Some("no") => Ok(false),
+      Some("true") | Some("1") | Some("yes") => Ok(true),
+      Some(a) => Err(format!("invalid admin flag: {}", a)),
+    };
+    let data = fields
+      .get(3)
+      .filter(|d| !d.is_empty())
+      .map(|d| d.to_string());
+    rows.push((
+      (i + 1) as i64,
+      if email.is_empty() {
+        Err("missing email".to_string())
+      } else {
+        admin.map(|admin| ImportUser {
+          name: name.to_string(),
+          email: email.to_string(),
+          admin,
+          data,
+        })
+      },
+    ));
+  }
+  rows
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -89,4 +178,54 @@ mod tests {
       Err(_) => assert_eq!(2, 4),
     }
   }
+
+  #[test]
+  fn test_normalize_email() {
+    assert_eq!(
+      normalize_email(" Bob@Example.COM "),
+      Some("bob@example.com".to_string())
+    );
+    assert_eq!(normalize_email("  "), None);
+    assert_eq!(normalize_email("phantom"), None);
+  }
+
+  #[test]
+  fn test_domain_matches() {
+    assert_eq!(
+      email_domain("Bob@Mail.Example.com"),
+      Some("mail.example.com".to_string())
+    );
+    assert!(domain_matches("Example.com", "example.com"));
+    assert!(!domain_matches("example.com", "mail.example.com"));
+    assert!(domain_matches("*.example.com", "mail.example.com"));
+    assert!(!domain_matches("*.example.com", "example.com"));
+    assert!(!domain_matches("*.example.com", "badexample.com"));
+    assert!(valid_domain_pattern("*.example.com"));
+    assert!(!valid_domain_pattern("bob@example.com"));
+    assert!(!valid_domain_pattern("*"));
+  }
+
+  #[test]
+  fn test_parse_import_csv() {
+    let rows = parse_import_csv(
+      "name,email,admin,data\n\
+       bob, bob@example.com ,,\n\
+       \n\
+       sue,sue@example.com,true,{\"a\": 1, \"b\": 2}\n\
+       jo\n\
+       al,al@example.com,maybe",
+    );
+    assert_eq!(rows.len(), 4);
+    let (row, bob) = &rows[0];
+    assert_eq!(*row, 2);
+    let bob = bob.as_ref().unwrap();
+    assert_eq!(bob.email, "bob@example.com");
+    assert!(!bob.admin);
+    assert_eq!(bob.data, None);
+    let sue = rows[1].1.as_ref().unwrap();
+    assert!(sue.admin);
+    assert_eq!(sue.data, Some("{\"a\": 1, \"b\": 2}".to_string()));
+    assert_eq!(rows[2].1.as_ref().err(), Some(&"missing email".to_string()));
+    assert!(rows[3].1.is_err());
+  }
 }